@@ -1,12 +1,14 @@
-use std::{fs, path::PathBuf};
+//! The browser module provides a frontend accessible by http
+//! The following functionality are exposed to other modules
 
-/// The browser module provides a frontend accessible by http
-/// The following functionality are exposed to other modules
+use std::{collections::HashMap, path::PathBuf};
 
 // Constants
 static HTML_HEAD_FILENAME: &str = "head.html";
 static HTML_BODY_FILENAME: &str = "body.html";
 static HTML_HOME_FILENAME: &str = "home.html";
+static HTML_INPUT_FILENAME: &str = "input.html";
+static HTML_MULTILINE_INPUT_FILENAME: &str = "input-multiline.html";
 static HTML_DEFAULT_HOMEPAGE: &str = "
 <h1>Welcome to Project Dioscuri!</h1>
 <h2>A hackable, accessible Gemini client.</h2>
@@ -20,90 +22,199 @@ static HTML_DEFAULT_HOMEPAGE: &str = "
 ";
 
 static HTML_DEFAULT_INPUT: &str = "
-<form method=\"get\"><label><input type=\"text\" name=\"query\"></label><input type=\"submit\" value=\"Submit\"></form>
+<form method=\"get\" class=\"dioscuri-input\" data-max-bytes=\"<DioscuriInputMaxBytes/>\"><label><input type=\"text\" name=\"dioscuri_input\"></label><input type=\"submit\" value=\"Submit\"><output class=\"dioscuri-input-counter\"></output></form>
+<script src=\"/.dioscuri/input.js\" defer></script>
+";
+
+static HTML_MULTILINE_INPUT: &str = "
+<form method=\"get\" class=\"dioscuri-input\" data-max-bytes=\"<DioscuriInputMaxBytes/>\"><label><textarea name=\"dioscuri_input\" rows=\"8\" cols=\"60\"></textarea></label><input type=\"submit\" value=\"Submit\"><output class=\"dioscuri-input-counter\"></output></form>
+<script src=\"/.dioscuri/input.js\" defer></script>
+";
+
+static HTML_SENSITIVE_INPUT: &str = "
+<form method=\"post\" class=\"dioscuri-input\" data-max-bytes=\"<DioscuriInputMaxBytes/>\" autocomplete=\"off\"><label><input type=\"password\" name=\"dioscuri_input\" autocomplete=\"off\"></label><input type=\"submit\" value=\"Submit\"><output class=\"dioscuri-input-counter\"></output></form>
+<script src=\"/.dioscuri/input.js\" defer></script>
+";
+
+/// Counts the bytes left while typing an answer to an input prompt, served at /.dioscuri/input.js
+static INPUT_SCRIPT: &str = include_str!("input.js");
+/// The most bytes a gemini request url may have
+const GEMINI_MAX_URL_BYTES: usize = 1024;
+
+static HTML_DEFAULT_ERROR: &str = "
+<section class=\"dioscuri-error\" data-status=\"<DioscuriErrorCode/>\">
+<h1><span class=\"dioscuri-error-code\"><DioscuriErrorCode/></span> <DioscuriErrorStatus/></h1>
+<p class=\"dioscuri-error-meta\"><DioscuriErrorMeta/></p>
+<p class=\"dioscuri-error-url\"><DioscuriErrorUrl/></p>
+<DioscuriErrorDetails/>
+<p class=\"dioscuri-error-links\"><DioscuriErrorLinks/></p>
+</section>
 ";
 
 static COMPONENT_MAIN: &str = "<Dioscuri/>";
 static COMPONENT_PROMPT: &str = "<DioscuriPrompt/>";
 static COMPONENT_INPUT: &str = "<DioscuriInput/>";
+static COMPONENT_SENSITIVE_INPUT: &str = "<DioscuriSensitiveInput/>";
+static COMPONENT_INPUT_MAX_BYTES: &str = "<DioscuriInputMaxBytes/>";
+static COMPONENT_ERROR_CODE: &str = "<DioscuriErrorCode/>";
+static COMPONENT_ERROR_STATUS: &str = "<DioscuriErrorStatus/>";
+static COMPONENT_ERROR_META: &str = "<DioscuriErrorMeta/>";
+static COMPONENT_ERROR_URL: &str = "<DioscuriErrorUrl/>";
+static COMPONENT_ERROR_DETAILS: &str = "<DioscuriErrorDetails/>";
+static COMPONENT_ERROR_LINKS: &str = "<DioscuriErrorLinks/>";
+
+const TOKEN_PARAM: &str = "dioscuri_token";
+const TOKEN_COOKIE: &str = "dioscuri_token";
+/// Form field holding the answer to an input prompt
+const INPUT_PARAM: &str = "dioscuri_input";
 
+static PASSTHROUGH_POLICY: &str = "sandbox; default-src 'none'; img-src 'self' data:; media-src 'self'; style-src 'unsafe-inline'";
+
+
+use std::{convert::Infallible, sync::Arc};
 
 use axum::{
-    body::Body, extract::Path, http::{self, Uri}, response::{Html, IntoResponse, Response}, routing::get, Router
+    body::Body, extract::{Path, Query, Request, State}, http::{self, HeaderMap, Uri}, middleware::{self, Next}, response::{Html, IntoResponse, Redirect, Response}, routing::{get, post}, Form, Router
 };
+use futures_util::stream;
+use serde::Deserialize;
+use url::form_urlencoded;
 
-use crate::{gemini::{get_gemini, StatusCode}, gemtext::gemtext_to_html};
+use crate::{cache, history, config::{self, Config, NetworkConfig}, resource, theme::{self, ActiveTheme, ThemeFileError}, gemini::{gemini_request_url, GeminiBody, GeminiClient, GeminiError, GeminiStream, MediaType, StatusCode}, gemtext::{escape_html, gemtext_to_html, GemtextRenderer}, identity, tofu::{self, TofuError}};
 
 /// Starts a HTTP server that acts as a proxy between gemini servers and the user interacting via a browser
-/// This is a blocking function.
-pub fn start_browser()  {
-    _browser_setup_directory();
+/// This is a blocking function. It returns an error if the server could not be started.
+pub fn start_browser(config: Config) -> Result<(), String> {
     match tokio::runtime::Runtime::new() {
-        Ok(runtime) => {
-            runtime.block_on(start_axum());
-        },
-        Err(e) => {
-            println!("Error launching tokio runtime: {e}\nGoodbye!")
-        }
+        Ok(runtime) => runtime.block_on(start_axum(config.network)),
+        Err(e) => Err(format!("Error launching tokio runtime: {e}\nGoodbye!")),
     }
 }
 
-async fn start_axum(){
-    let app = Router::new()
+/// Returns the proxy as a Router, to be served on its own or nested in another axum app.
+/// Proxy paths are absolute, so nest it at the root of a host or port, e.g. with axum::serve.
+/// If network.token is set, every request must carry it. Everything else follows the process wide configuration,
+/// see config::config_init.
+pub fn browser_router(network: &NetworkConfig) -> Router {
+    _browser_setup_directory();
+    let mut app = Router::new()
         .route("/", get(get_home))
-        .route("/{*url}", get(get_normal))
+        .route("/{*url}", get(get_normal).post(post_sensitive))
         .route("/.src/{*path}", get(get_resource))
+        .route("/.dioscuri/tofu", post(post_tofu))
+        .route("/.dioscuri/cached/{*url}", get(get_cached))
+        .route("/.dioscuri/input.js", get(get_input_script))
+        .route("/.dioscuri/identity", post(post_identity))
+        .route("/.dioscuri/settings", get(get_settings).post(post_settings))
+        .route("/.dioscuri/themes", get(get_themes).post(post_themes))
+        .route("/.dioscuri/history", get(get_history).post(post_history))
         ;
-
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:1965").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    if let Some(token) = network.token.clone() {
+        app = app.layer(middleware::from_fn_with_state(Arc::new(token), require_token));
+    }
+    app
 }
 
-/// Sets up the browser resource directory
-fn _browser_setup_directory() {
-    let home_dir = dirs::home_dir().ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::NotFound, "Home directory does not exist?")
-    }).unwrap();
-    let dioscuri_dir = home_dir.join(".dioscuri/browser");
-    if !dioscuri_dir.exists() {
-        fs::create_dir_all(&dioscuri_dir).unwrap();
-        println!("Creating directory: {:?}", dioscuri_dir);
+async fn start_axum(network: NetworkConfig) -> Result<(), String> {
+    let app = browser_router(&network);
+    let addr = network.socket_addr();
+    let listener = tokio::net::TcpListener::bind(addr).await.map_err(|e| format!(
+        "Could not listen on {addr}: {e}\nIs another program (or another Dioscuri) using that port? Pick another one with --port or in config.toml."
+    ))?;
+    println!("Welcome to Project Dioscuri!\nAccess gemini by opening http://{addr}/ on any web browser!");
+    if !addr.ip().is_loopback() {
+        match network.token {
+            Some(_) => println!("Other machines can reach this proxy. Open http://{addr}/?{TOKEN_PARAM}={{your token}} once in each browser."),
+            None => println!("Warning: other machines can reach this proxy and no token is set. Anyone on your network can browse through it."),
+        }
     }
+    axum::serve(listener, app).await.map_err(|e| format!("The proxy stopped: {e}"))
 }
 
-/// Returns ~/.dioscuri/browser
-/// Assumes that the folder has been setup properly
-fn get_resource_dir() -> PathBuf {
-    let home_dir = dirs::home_dir().ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::NotFound, "Home directory does not exist?")
-    }).unwrap();
-    let dioscuri_dir = home_dir.join(".dioscuri/browser");
-    if !dioscuri_dir.exists() {
-        let _ = fs::create_dir_all(&dioscuri_dir);
-        println!("Creating directory: {:?}", dioscuri_dir);
+/// Lets a request through if it carries the token, either in the cookie or once in the query.
+/// A token in the query sets the cookie and redirects to the same page without it, so it does not stay in the address bar.
+async fn require_token(State(token): State<Arc<String>>, request: Request, next: Next) -> Response {
+    let uri = request.uri().clone();
+    if let Some(query) = uri.query() {
+        let (given, rest) = split_token_param(query);
+        if given.is_some_and(|given| token_matches(&given, &token)) {
+            let location = match rest {
+                Some(rest) => format!("{}?{}", uri.path(), rest),
+                None => uri.path().to_string(),
+            };
+            let cookie = format!("{TOKEN_COOKIE}={}; Path=/; HttpOnly; SameSite=Strict", token);
+            let mut response = Redirect::to(&location).into_response();
+            if let Ok(value) = http::HeaderValue::from_str(&cookie) {
+                response.headers_mut().insert(http::header::SET_COOKIE, value);
+            }
+            return response;
+        }
+    }
+    if get_cookie(request.headers(), TOKEN_COOKIE).is_some_and(|value| token_matches(value, &token)) {
+        return next.run(request).await;
     }
-    dioscuri_dir
+    (http::StatusCode::UNAUTHORIZED, Html(format!(
+        "<h1>Token required</h1><p>This Dioscuri proxy is protected by a token. Open <code>/?{TOKEN_PARAM}=your-token</code> to continue.</p>"
+    ))).into_response()
+}
+
+/// Takes the dioscuri_token pair out of a query string. Returns the token and what is left of the query, if anything.
+fn split_token_param(query: &str) -> (Option<String>, Option<String>) {
+    let mut token = None;
+    let rest: Vec<&str> = query.split('&')
+        .filter(|pair| match pair.split_once('=') {
+            Some((TOKEN_PARAM, value)) => {
+                token = Some(value.to_string());
+                false
+            },
+            _ => true,
+        })
+        .collect();
+    let rest = (!rest.is_empty()).then(|| rest.join("&"));
+    (token, rest)
+}
+
+/// Compares tokens in time independent of where they first differ
+fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Sets up the theme directories
+fn _browser_setup_directory() {
+    theme::theme_setup_directories();
+}
+
+/// Returns the value of the first cookie called name, if any
+fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get_all(http::header::COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Returns the theme this request is rendered with, see theme::theme_active
+fn get_theme(headers: &HeaderMap) -> ActiveTheme {
+    theme::theme_active(get_cookie(headers, theme::THEME_COOKIE))
 }
 
-/// Searches the resource directory for body.html 
+/// Searches the theme for body.html 
 /// if does not exist, return ""
-fn load_body() -> String {
-    let path = get_resource_dir().join(HTML_BODY_FILENAME);
-    fs::read_to_string(path).unwrap_or_else(|_| "".to_string())
+fn load_body(theme: &ActiveTheme) -> String {
+    theme.read_to_string(HTML_BODY_FILENAME).unwrap_or_default()
 }
 
-/// Searches the resource directory for head.html
+/// Searches the theme for head.html
 /// if does not exist, return ""
-fn load_header() -> String {
-    let path = get_resource_dir().join(HTML_HEAD_FILENAME);
-    fs::read_to_string(path).unwrap_or_else(|_| "".to_string())
+fn load_header(theme: &ActiveTheme) -> String {
+    theme.read_to_string(HTML_HEAD_FILENAME).unwrap_or_default()
 }
 
-/// Searches the resource directory for home.html
+/// Searches the theme for home.html
 /// if does not exist, return HTML_DEFAULT_HOMEPAGE
-fn load_home() -> String {
-    let path = get_resource_dir().join(HTML_HOME_FILENAME);
-    fs::read_to_string(path).unwrap_or_else(|_| HTML_DEFAULT_HOMEPAGE.to_string())
+fn load_home(theme: &ActiveTheme) -> String {
+    theme.read_to_string(HTML_HOME_FILENAME).unwrap_or_else(|| HTML_DEFAULT_HOMEPAGE.to_string())
 }
 
 /// Loads head.html concat body.html.
@@ -112,8 +223,8 @@ fn load_home() -> String {
 /// append them to the back of the skeleton.
 /// this ensures that the skeleton html content can properly render all content regardless
 /// of the existence of head.html and body.html
-fn load_skeleton() -> String {
-    let mut skeleton = format!("{}{}",load_header(), load_body());
+fn load_skeleton(theme: &ActiveTheme) -> String {
+    let mut skeleton = format!("{}{}",load_header(theme), load_body(theme));
     if !skeleton.contains(COMPONENT_MAIN){
         skeleton.push_str(COMPONENT_MAIN);
     }
@@ -127,129 +238,1222 @@ fn load_skeleton() -> String {
 }
 
 /// returns .dioscuri/browser/home.html, else a default if not found
-async fn get_home() -> Html<String>{
-    return Html(format!("{}{}",load_header(),load_home()));
+/// If rendering.homepage is set, redirects to that capsule instead
+async fn get_home(headers: HeaderMap) -> Response {
+    if let Some(homepage) = &config::config_get().rendering.homepage {
+        let homepage = homepage.strip_prefix("gemini://").unwrap_or(homepage);
+        return Redirect::to(&format!("/{}", homepage)).into_response();
+    }
+    let theme = get_theme(&headers);
+    with_page_policy(Html(format!("{}{}",load_header(&theme),load_home(&theme))).into_response(), &headers)
+}
+
+/// Loads the skeleton and injects the components.
+/// All remote text must already be escaped, the response carries the page CSP as a second line of defence.
+fn render_page(headers: &HeaderMap, main: &str, prompt: &str, input: &str) -> Response {
+    let skeleton = load_skeleton(&get_theme(headers));
+    // inject the components
+    let res = skeleton.replace(COMPONENT_MAIN, main)
+                            .replace(COMPONENT_INPUT, input)
+                            .replace(COMPONENT_SENSITIVE_INPUT, "")
+                            .replace(COMPONENT_PROMPT, prompt);
+    with_page_policy(Html(res).into_response(), headers)
+}
+
+/// Renders the prompt of a sensitive input request (status 11) with a password field.
+/// The field goes in <DioscuriSensitiveInput/> if the theme has it, else in <DioscuriInput/>.
+fn render_sensitive_input_page(headers: &HeaderMap, prompt: &str, input: &str) -> Response {
+    let res = inject_sensitive_input(&load_skeleton(&get_theme(headers)), prompt, input);
+    with_page_policy(Html(res).into_response(), headers)
 }
 
-/// Given a query {foo}={bar}, where bar can include more queries,
-/// return {bar}
-/// if no '=' delimiter exists, then return query
-fn strip_first_url_query_key(query: String) -> String {
-    if let Some((_, right)) = query.split_once('=') {
-        right.to_string()
+fn inject_sensitive_input(skeleton: &str, prompt: &str, input: &str) -> String {
+    let res = if skeleton.contains(COMPONENT_SENSITIVE_INPUT) {
+        skeleton.replace(COMPONENT_SENSITIVE_INPUT, input).replace(COMPONENT_INPUT, "")
     } else {
-        query
+        skeleton.replace(COMPONENT_INPUT, input)
+    };
+    res.replace(COMPONENT_MAIN, "").replace(COMPONENT_PROMPT, prompt)
+}
+
+/// Renders a gemtext body into the skeleton while it arrives.
+/// The skeleton up to <Dioscuri/> is sent right away, then the html of each line once it is complete,
+/// then the rest of the skeleton when the capsule closes the connection. The page fills <Dioscuri/> only once.
+/// The body is only read as fast as the browser takes the page, so a slow browser slows the capsule down.
+/// A complete page of at most cache::CACHE_MAX_BODY bytes is kept by the cache module under cache_url, if given.
+/// The first heading becomes the title of history_url in the history module, if given.
+fn render_gemtext_stream(headers: &HeaderMap, url: String, meta: &str, body: GeminiBody, cache_url: Option<String>, history_url: Option<String>) -> Response {
+    let skeleton = load_skeleton(&get_theme(headers))
+        .replace(COMPONENT_INPUT, "")
+        .replace(COMPONENT_SENSITIVE_INPUT, "")
+        .replace(COMPONENT_PROMPT, "");
+    let (head, tail) = skeleton.split_once(COMPONENT_MAIN).unwrap_or((&skeleton, ""));
+    let page = GemtextPage {
+        head: Some(head.to_string()),
+        tail: tail.replace(COMPONENT_MAIN, ""),
+        body,
+        decoder: MediaType::parse(meta).decoder(),
+        renderer: Some(GemtextRenderer::new(&url)),
+        cached: cache_url.map(|url| (url, meta.to_string(), Vec::new())),
+        history_url,
+    };
+    let html = stream::unfold(page, |mut page| async move {
+        page.next().await.map(|html| (Ok::<_, Infallible>(html), page))
+    });
+    with_page_policy(Html(Body::from_stream(html)).into_response(), headers)
+}
+
+/// The state of a page streamed by render_gemtext_stream
+struct GemtextPage {
+    /// The skeleton before <Dioscuri/>, until it is sent
+    head: Option<String>,
+    /// The skeleton after <Dioscuri/>
+    tail: String,
+    body: GeminiBody,
+    decoder: encoding_rs::Decoder,
+    /// None once the body ended
+    renderer: Option<GemtextRenderer>,
+    /// (url, meta, body so far) of a page that will be cached
+    cached: Option<(String, String, Vec<u8>)>,
+    /// The history url to give the first heading to, until it is found
+    history_url: Option<String>,
+}
+
+impl GemtextPage {
+    /// Returns the next piece of html, or None once the page is complete.
+    /// If the body fails midway, what arrived stays on the page, followed by the error.
+    async fn next(&mut self) -> Option<String> {
+        if let Some(head) = self.head.take() {
+            return Some(head);
+        }
+        loop {
+            let renderer = self.renderer.as_mut()?;
+            match self.body.chunk().await {
+                Ok(Some(chunk)) => {
+                    if let Some((_, _, cached)) = &mut self.cached {
+                        cached.extend_from_slice(&chunk);
+                        if cached.len() > cache::CACHE_MAX_BODY {
+                            self.cached = None;
+                        }
+                    }
+                    let html = match renderer.push_text(&decode_chunk(&mut self.decoder, &chunk, false)) {
+                        Ok(html) => html,
+                        Err(e) => return self.fail("Line Too Long", &e.to_string()),
+                    };
+                    if let Some(title) = renderer.title() {
+                        if let Some(url) = self.history_url.take() {
+                            history::history_set_title(&url, title);
+                        }
+                    }
+                    if !html.is_empty() {
+                        return Some(html);
+                    }
+                },
+                Ok(None) => {
+                    let mut html = match renderer.push_text(&decode_chunk(&mut self.decoder, &[], true)) {
+                        Ok(html) => html,
+                        Err(e) => return self.fail("Line Too Long", &e.to_string()),
+                    };
+                    let renderer = self.renderer.take()?;
+                    html.push_str(&renderer.finish());
+                    html.push_str(&self.tail);
+                    if let Some((url, meta, body)) = self.cached.take() {
+                        cache::cache_store(&url, &meta, &body);
+                    }
+                    return Some(html);
+                },
+                Err(e) => return self.fail(e.as_str(), &e.to_string()),
+            }
+        }
+    }
+
+    /// Ends the page early with error and its details after what was rendered so far.
+    /// The page is not cached, and the capsule is not read from anymore.
+    fn fail(&mut self, error: &str, details: &str) -> Option<String> {
+        let mut html = self.renderer.take()?.finish();
+        html.push_str(&format!(
+            "<p class=\"dioscuri-stream-error\"><strong>{}</strong> {}</p>\n{}",
+            error, escape_html(details), self.tail
+        ));
+        Some(html)
+    }
+}
+
+/// Decodes the next piece of a text body. Characters split between pieces are completed by the next piece.
+fn decode_chunk(decoder: &mut encoding_rs::Decoder, bytes: &[u8], last: bool) -> String {
+    let capacity = decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len() * 3 + 16);
+    let mut text = String::with_capacity(capacity);
+    let _ = decoder.decode_to_string(bytes, &mut text, last);
+    text
+}
+
+/// Builds the form injected in <DioscuriInput/> for a prompt on url.
+/// Uses input.html, or input-multiline.html if multiline is set, from the theme, else the built in form.
+/// <DioscuriInputMaxBytes/> in the form is replaced with how many bytes the answer may have.
+fn input_html(theme: &ActiveTheme, url: &str, multiline: bool) -> String {
+    let template = if multiline {
+        theme.read_to_string(HTML_MULTILINE_INPUT_FILENAME).unwrap_or_else(|| HTML_MULTILINE_INPUT.to_string())
+    } else {
+        theme.read_to_string(HTML_INPUT_FILENAME).unwrap_or_else(|| HTML_DEFAULT_INPUT.to_string())
+    };
+    template.replace(COMPONENT_INPUT_MAX_BYTES, &input_max_bytes(url).to_string())
+}
+
+/// Returns how many bytes are left for the answer to a prompt on url once gemini://{url}? is sent
+fn input_max_bytes(url: &str) -> usize {
+    GEMINI_MAX_URL_BYTES.saturating_sub(format!("gemini://{url}?").len())
+}
+
+/// Serves the script counting the bytes left in input forms
+async fn get_input_script() -> Response {
+    Response::builder()
+        .header(http::header::CONTENT_TYPE, "text/javascript; charset=utf-8")
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .header(http::header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(Body::from(INPUT_SCRIPT))
+        .unwrap()
+}
+
+/// Keeps pages around sensitive input out of the browser's cache, and their url out of Referer headers
+fn with_sensitive_policy(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(http::header::CACHE_CONTROL, http::HeaderValue::from_static("no-store"));
+    headers.insert(http::header::REFERRER_POLICY, http::HeaderValue::from_static("no-referrer"));
+    response
+}
+
+/// Adds the Content-Security-Policy used on every page built from the theme.
+/// Scripts may only be loaded from /.src, so inline scripts and scripts from capsules or other sites never run.
+/// Styles, fonts and images may still come from https, which the bundled themes use.
+fn with_page_policy(mut response: Response, headers: &HeaderMap) -> Response {
+    let script_src = headers.get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .filter(|host| is_plain_host(host))
+        .map(|host| format!("http://{0}/.src/ http://{0}/.dioscuri/input.js", host))
+        .unwrap_or_else(|| "'none'".to_string());
+    let policy = format!(
+        "default-src 'self'; script-src {}; style-src 'self' 'unsafe-inline' https:; font-src 'self' https: data:; \
+        img-src 'self' https: data:; connect-src 'self'; object-src 'none'; base-uri 'none'; form-action 'self'; frame-ancestors 'none'",
+        script_src
+    );
+    let headers = response.headers_mut();
+    if let Ok(value) = http::HeaderValue::from_str(&policy) {
+        headers.insert(http::header::CONTENT_SECURITY_POLICY, value);
+    }
+    headers.insert(http::header::X_CONTENT_TYPE_OPTIONS, http::HeaderValue::from_static("nosniff"));
+    response
+}
+
+/// Returns true if a Host header is a plain host[:port] that can be put in a CSP source expression
+fn is_plain_host(host: &str) -> bool {
+    !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c))
+}
+
+/// The query sent along with a proxied url
+#[derive(Debug, PartialEq)]
+enum ProxyQuery {
+    /// The answer typed in an input form, still to be encoded for gemini
+    Input(String),
+    /// A query that is already part of the gemini url, e.g. from a link, sent untouched
+    Raw(String),
+}
+
+/// Reads the query of a proxy url.
+/// Input forms send their answer as dioscuri_input={answer}, which is decoded from the form encoding.
+/// Any other query belongs to the gemini url itself and is kept as it is.
+fn parse_proxy_query(query: &str) -> ProxyQuery {
+    let mut pairs = form_urlencoded::parse(query.as_bytes());
+    match pairs.next() {
+        Some((key, value)) if key == INPUT_PARAM => ProxyQuery::Input(value.into_owned()),
+        _ => ProxyQuery::Raw(query.to_string()),
     }
 }
 
 /// Receives a url and handles it.
 /// If the url has no query, forward it to the gemini server and return the result.
-/// If the url has a query, only process the first query if multiple exist as per protocol specification
+/// Successful gemtext responses are rendered into the skeleton, any other media type is passed through as-is.
+/// Both are streamed to the browser while they arrive, see render_gemtext_stream and passthrough_body.
+/// If the url has a query, it is either the answer from an input form or part of the gemini url, see parse_proxy_query
+/// If the capsule redirects, or the answer to an input form was sent, the browser is sent to the final url,
+/// so its address bar and relative links match the page. The response waits there in the cache module.
+/// If the browser disconnects mid-request, axum drops this future and the gemini request is cancelled with it.
 async fn get_normal(
     Path(url): Path<String>,
     uri: Uri,
-) -> Html<String> {
+    headers: HeaderMap,
+) -> Response {
+    let back = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    if let Some(response) = cache::cache_take_redirected(back) {
+        // the path lost its query, the response still has it
+        let page_url = proxy_url(&response.url).to_string();
+        return render_response(page_url, false, true, Ok(response), back, &headers);
+    }
     // Check if there are any query parameters
-    let mut gem_url = url.clone();
-    if let Some(q) = uri.query() {
-        let query = strip_first_url_query_key(q.to_string());
-        gem_url = format!("{url}?{query}");
-    } 
-    let (status, header, body) = get_gemini(gem_url);
+    let query = uri.query().map(parse_proxy_query);
+    let requested = match &query {
+        Some(ProxyQuery::Input(_)) => None,
+        Some(ProxyQuery::Raw(query)) => Some(gemini_request_url(&format!("{url}?{query}"))),
+        None => Some(gemini_request_url(&url)),
+    };
+    match fetch_page(&url, &query).await {
+        Ok(response) => {
+            let page_url = proxy_url(&response.url).to_string();
+            if requested.as_ref() != Some(&response.url) {
+                let location = location_path(&page_url);
+                if let Ok(value) = http::HeaderValue::from_str(&location) {
+                    cache::cache_stash_redirected(&location, response);
+                    return (http::StatusCode::SEE_OTHER, [(http::header::LOCATION, value)]).into_response();
+                }
+            }
+            render_response(page_url, query.is_none(), true, Ok(response), back, &headers)
+        },
+        // failures are shown at the requested url, so retrying asks for it again
+        Err(error) => {
+            let shown_url = match &query {
+                Some(ProxyQuery::Raw(query)) => format!("{url}?{query}"),
+                _ => url,
+            };
+            render_response(shown_url, query.is_none(), false, Err(error), back, &headers)
+        },
+    }
+}
+
+/// Returns a gemini:// url as used in proxy paths, i.e. without gemini://
+fn proxy_url(gemini_url: &str) -> &str {
+    gemini_url.strip_prefix("gemini://").unwrap_or(gemini_url)
+}
+
+/// Returns the proxy path for page_url as sent in a Location header and requested back by the browser.
+/// Raw queries may hold spaces and other characters the browser would percent encode before following,
+/// so they are encoded here and the redirected response is stashed under the path the browser asks for.
+/// Existing percent escapes are kept as they are.
+fn location_path(page_url: &str) -> String {
+    let mut path = String::with_capacity(page_url.len() + 1);
+    path.push('/');
+    for c in page_url.chars() {
+        if c.is_ascii_graphic() && !"\"#'<>\\^`{|}".contains(c) {
+            path.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                path.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    path
+}
+
+/// Receives the answer to a sensitive input prompt (status 11).
+/// The answer comes in the form body, so it never shows up in the address bar or the browser's history.
+async fn post_sensitive(
+    Path(url): Path<String>,
+    headers: HeaderMap,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    if !is_same_origin(&headers) {
+        return (http::StatusCode::FORBIDDEN, "Input can only be sent from Dioscuri itself").into_response();
+    }
+    let query = form.get(INPUT_PARAM).map(|value| ProxyQuery::Input(value.clone()));
+    let response = fetch_page(&url, &query).await;
+    let final_url = match &response {
+        Ok(response) => proxy_url(&response.url).to_string(),
+        Err(_) => url,
+    };
+    // the browser is not redirected and the page is shown without its query, which holds the input
+    let page_url = final_url.split('?').next().unwrap_or_default().to_string();
+    // retrying asks for the input again instead of resending it
+    let back = format!("/{page_url}");
+    with_sensitive_policy(render_response(page_url, false, false, response, &back, &headers))
+}
+
+/// Forwards url, with query if any, to the gemini server and returns its response once the header arrived
+async fn fetch_page(url: &str, query: &Option<ProxyQuery>) -> Result<GeminiStream, GeminiError> {
+    let client = GeminiClient::from_config(&config::config_get());
+    match query {
+        Some(ProxyQuery::Input(input)) => client.get_input_stream(url, input).await,
+        Some(ProxyQuery::Raw(query)) => client.get_stream(&format!("{url}?{query}")).await,
+        None => client.get_stream(url).await,
+    }
+}
+
+/// Builds the page for the response of the capsule at url, written without gemini://.
+/// Successful gemtext responses are kept by the cache module if cacheable is set.
+/// The visit is recorded by the history module if history is set, unless the capsule asks for sensitive input.
+/// back is the proxy path that retries the request.
+fn render_response(url: String, cacheable: bool, history: bool, response: Result<GeminiStream, GeminiError>, back: &str, headers: &HeaderMap) -> Response {
+    let response = match response {
+        Ok(response) => response,
+        Err(GeminiError::Tofu(TofuError::Mismatch { key, .. } | TofuError::Expired { key, .. })) => {
+            let mut response = render_page(headers, &tofu_warning_html(&key, back), "", "");
+            *response.status_mut() = http::StatusCode::BAD_GATEWAY;
+            return response;
+        },
+        Err(error) => {
+            let meta = error.to_string();
+            let page = ErrorPage { code: None, status: error.as_str(), meta: &meta, url: &url, back };
+            let mut response = render_page(headers, &error_html(&get_theme(headers), &page, ""), "", "");
+            *response.status_mut() = match error {
+                GeminiError::Timeout { .. } => http::StatusCode::GATEWAY_TIMEOUT,
+                _ => http::StatusCode::BAD_GATEWAY,
+            };
+            return response;
+        },
+    };
+    let GeminiStream { status, raw_code, meta, body, .. } = response;
+    let history = history && status != StatusCode::InputSensitive;
+    if history {
+        history::history_record(&url, raw_code);
+    }
     match status {
         StatusCode::Success => {
-            let html = gemtext_to_html(body, url);
-            let skeleton = load_skeleton();
-            // inject the components
-            let res = skeleton.replace(COMPONENT_MAIN, &html)
-                                    .replace(COMPONENT_INPUT, "")
-                                    .replace(COMPONENT_PROMPT, "");
-            return Html(res)
+            let media = MediaType::parse(&meta);
+            if !media.is_gemtext() {
+                return passthrough_response(&media, passthrough_body(body));
+            }
+            // pages fetched with a query can hold user input, so only plain urls are cached
+            let cache_url = (cacheable && !url.contains('?')).then(|| url.clone());
+            let history_url = history.then(|| url.clone());
+            render_gemtext_stream(headers, url, &meta, body, cache_url, history_url)
         },
         StatusCode::InputExpected => {
-            let skeleton = load_skeleton();
-            // inject the components
-            let res = skeleton.replace(COMPONENT_MAIN, "")
-                                    .replace(COMPONENT_INPUT, HTML_DEFAULT_INPUT)
-                                    .replace(COMPONENT_PROMPT, &header);
-            return Html(res);
+            let input = input_html(&get_theme(headers), &url, config::config_get().rendering.multiline_input);
+            render_page(headers, "", &escape_html(&meta), &input)
         },
         StatusCode::InputSensitive => {
-            let skeleton = load_skeleton();
-            // inject the components
-            let res = skeleton.replace(COMPONENT_MAIN, "")
-                                    .replace(COMPONENT_INPUT, HTML_DEFAULT_INPUT)
-                                    .replace(COMPONENT_PROMPT, &header);
-            return Html(res);
+            let input = HTML_SENSITIVE_INPUT.replace(COMPONENT_INPUT_MAX_BYTES, &input_max_bytes(&url).to_string());
+            with_sensitive_policy(render_sensitive_input_page(headers, &escape_html(&meta), &input))
+        },
+        StatusCode::FailureCertNeeded | StatusCode::FailureCertUnauthorized | StatusCode::FailureCertInvalid => {
+            let page = ErrorPage { code: Some(raw_code), status: status.as_str(), meta: &meta, url: &url, back };
+            let html = error_html(&get_theme(headers), &page, &identity_html(&url, back));
+            with_gemini_status(render_page(headers, &html, "", ""), &status, &meta)
         },
         _ => {
-            let skeleton = load_skeleton();
-            // inject the components
-            let res = skeleton.replace(COMPONENT_MAIN, &header)
-                                    .replace(COMPONENT_INPUT, "")
-                                    .replace(COMPONENT_PROMPT, "");
-            return Html(res);
+            let page = ErrorPage { code: Some(raw_code), status: status.as_str(), meta: &meta, url: &url, back };
+            let html = error_html(&get_theme(headers), &page, "");
+            with_gemini_status(render_page(headers, &html, "", ""), &status, &meta)
         }
     } 
 }
 
-/// Searches ~/.dioscuri/browser/{my_path_to_file} by extracting my_path_to_file
-/// The filepath must only exist within the browser/ folder for security concerns
-async fn get_resource(Path(filepath): Path<String>) -> impl IntoResponse {
-    // get the resource directory first
-    let parent_dir = get_resource_dir();
-    let resource_path = parent_dir.join(filepath);
+/// Returns the http status a proxied page with this gemini status is served with.
+/// Failures of the capsule are gateway errors, so they never look like a working page.
+fn http_status_for(status: &StatusCode) -> http::StatusCode {
+    match status {
+        StatusCode::InputExpected | StatusCode::InputSensitive | StatusCode::Success => http::StatusCode::OK,
+        StatusCode::FailureServerTemp | StatusCode::FailureServerUnavailable | StatusCode::FailureServerSlowdown => {
+            http::StatusCode::SERVICE_UNAVAILABLE
+        },
+        StatusCode::FailureServerNotfound => http::StatusCode::NOT_FOUND,
+        StatusCode::FailureServerGone => http::StatusCode::GONE,
+        StatusCode::FailureServerBadReq => http::StatusCode::BAD_REQUEST,
+        StatusCode::FailureCertNeeded => http::StatusCode::UNAUTHORIZED,
+        StatusCode::FailureCertUnauthorized | StatusCode::FailureCertInvalid => http::StatusCode::FORBIDDEN,
+        StatusCode::FailureServerCgiError | StatusCode::FailureServerProxyError | StatusCode::FailureServerProxyrefused
+        | StatusCode::FailureServer | StatusCode::RedirectTemp | StatusCode::RedirectPerm | StatusCode::StatusUnknown => http::StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Sets the http status of a rendered page to match its gemini status.
+/// For 44 Slow Down, META is the number of seconds to wait, which becomes Retry-After.
+fn with_gemini_status(mut response: Response, status: &StatusCode, meta: &str) -> Response {
+    *response.status_mut() = http_status_for(status);
+    if *status == StatusCode::FailureServerSlowdown {
+        let seconds = meta.trim();
+        if !seconds.is_empty() && seconds.chars().all(|c| c.is_ascii_digit()) {
+            if let Ok(value) = http::HeaderValue::from_str(seconds) {
+                response.headers_mut().insert(http::header::RETRY_AFTER, value);
+            }
+        }
+    }
+    response
+}
+
+/// A failed request, as shown on the error page
+struct ErrorPage<'a> {
+    /// The code sent by the capsule, None if it could not be reached
+    code: Option<u8>,
+    /// The name of the status, or of the GeminiError
+    status: &'a str,
+    /// The META sent by the capsule, or the reason the request failed
+    meta: &'a str,
+    /// The requested url, without gemini://
+    url: &'a str,
+    /// The proxy path that retries the request
+    back: &'a str,
+}
+
+/// Returns the error templates that can render status, most specific first:
+/// error-51.html, error-5x.html, then error-cert.html for 6x or error-client.html for failures without a code, then error.html
+fn error_template_names(code: Option<u8>) -> Vec<String> {
+    let mut names = Vec::new();
+    match code {
+        Some(code) => {
+            names.push(format!("error-{code}.html"));
+            names.push(format!("error-{}x.html", code / 10));
+            if code / 10 == 6 {
+                names.push("error-cert.html".to_string());
+            }
+        },
+        None => names.push("error-client.html".to_string()),
+    }
+    names.push("error.html".to_string());
+    names
+}
+
+/// Renders the error component for a failed request with the theme's error template, or HTML_DEFAULT_ERROR.
+/// details is trusted html placed in <DioscuriErrorDetails/>, e.g. the identity forms for 6x statuses.
+fn error_html(theme: &ActiveTheme, page: &ErrorPage, details: &str) -> String {
+    let template = error_template_names(page.code).iter()
+        .find_map(|name| theme.read_to_string(name))
+        .unwrap_or_else(|| HTML_DEFAULT_ERROR.to_string());
+    let mut links = format!("<a class=\"dioscuri-error-retry\" href=\"{}\">Retry</a>", escape_html(page.back));
+    if cache::cache_get(page.url).is_some() {
+        links.push_str(&format!(
+            " <a class=\"dioscuri-error-cached\" href=\"/.dioscuri/cached/{}\">View cached copy</a>",
+            escape_html(page.url)
+        ));
+    }
+    template.replace(COMPONENT_ERROR_CODE, &page.code.map(|code| code.to_string()).unwrap_or_default())
+        .replace(COMPONENT_ERROR_STATUS, page.status)
+        .replace(COMPONENT_ERROR_META, &escape_html(page.meta))
+        .replace(COMPONENT_ERROR_URL, &escape_html(&format!("gemini://{}", page.url)))
+        .replace(COMPONENT_ERROR_DETAILS, details)
+        .replace(COMPONENT_ERROR_LINKS, &links)
+}
 
-    // resolve symlinks and relative components
-    let Ok(resource_path_canon) = resource_path.canonicalize() else {
-        return (http::StatusCode::NOT_FOUND, "Invalid file path").into_response();
+/// Renders the last successful copy of a page kept by the cache module, with a note saying when it was fetched
+async fn get_cached(Path(url): Path<String>, headers: HeaderMap) -> Response {
+    let Some(page) = cache::cache_get(&url) else {
+        let html = format!("<h1>No cached copy</h1><p>Dioscuri has no copy of gemini://{} in memory.</p>", escape_html(&url));
+        let mut response = render_page(&headers, &html, "", "");
+        *response.status_mut() = http::StatusCode::NOT_FOUND;
+        return response;
     };
+    let note = format!(
+        "<p class=\"dioscuri-cached\">Cached copy from {}. <a href=\"/{}\">Load the current page</a></p>",
+        httpdate::fmt_http_date(page.stored), escape_html(&url)
+    );
+    let html = gemtext_to_html(MediaType::parse(&page.meta).decode_text(&page.body), url);
+    render_page(&headers, &format!("{note}{html}"), "", "")
+}
 
-    let Ok(parent_canon) = parent_dir.canonicalize() else {
-        return (http::StatusCode::INTERNAL_SERVER_ERROR, "Dioscuri's browser resource folder is invalid!").into_response();
+/// Builds the certificate warning shown when TOFU verification fails for key.
+/// back is the proxy path to return to once the user trusts the certificate.
+fn tofu_warning_html(key: &str, back: &str) -> String {
+    let (reason, old, new, can_pin) = match tofu::tofu_get_pending(key) {
+        Some(TofuError::Mismatch { old, new, .. }) => (
+            "The certificate presented by this capsule does not match the one Dioscuri trusted before. \
+            The capsule may have changed its certificate, or someone may be impersonating it.",
+            Some(old), new, true,
+        ),
+        Some(TofuError::Expired { new, not_yet_valid: false, .. }) => (
+            "The certificate presented by this capsule is expired. Many capsules never renew theirs, \
+            trusting it permanently keeps it trusted for as long as the capsule presents it.",
+            None, new, true,
+        ),
+        Some(TofuError::Expired { new, not_yet_valid: true, .. }) => (
+            "The certificate presented by this capsule is not yet valid. Check that your clock is right.",
+            None, new, false,
+        ),
+        _ => return format!("<h1>Certificate not trusted</h1><p>No pending certificate for {}. Try reloading the page.</p>", escape_html(key)),
     };
+    let row = |label: &str, info: &tofu::CertInfo| format!(
+        "<tr><td>{}</td><td><code>{}</code></td><td>{}</td></tr>",
+        label, escape_html(&info.fingerprint), escape_html(&info.not_after)
+    );
+    let mut html = format!(
+        "<h1>Certificate warning for {}</h1><p>{}</p>\
+        <table><tr><th></th><th>SHA-256 fingerprint</th><th>Expires</th></tr>",
+        escape_html(key), reason
+    );
+    if let Some(old) = &old {
+        html.push_str(&row("Trusted", old));
+    }
+    html.push_str(&row("Presented", &new));
+    html.push_str(&format!(
+        "</table><form method=\"post\" action=\"/.dioscuri/tofu\">\
+        <input type=\"hidden\" name=\"key\" value=\"{}\">\
+        <input type=\"hidden\" name=\"fingerprint\" value=\"{}\">\
+        <input type=\"hidden\" name=\"back\" value=\"{}\">\
+        <button type=\"submit\" name=\"action\" value=\"once\">Trust once</button>",
+        escape_html(key), escape_html(&new.fingerprint), escape_html(back)
+    ));
+    if can_pin {
+        html.push_str("<button type=\"submit\" name=\"action\" value=\"always\">Trust permanently</button>");
+    }
+    html.push_str("<button type=\"submit\" name=\"action\" value=\"reject\">Reject</button></form>");
+    html
+}
 
-    // resource should be a child of the browser folder
-    if !resource_path_canon.starts_with(&parent_canon) {
-        return (http::StatusCode::FORBIDDEN, "Access denied").into_response();
+/// Builds the identity forms shown on the error page when a capsule asks for a client certificate (status 60-62).
+/// It offers to create a new identity or to use an existing one, for the whole capsule or only the current directory.
+fn identity_html(url: &str, back: &str) -> String {
+    let Some((host_scope, path_scope)) = identity::identity_scopes_for_url(url) else {
+        return String::new();
+    };
+    let scope_choice = |checked: bool| {
+        let mut html = format!(
+            "<label><input type=\"radio\" name=\"scope\" value=\"{0}\"{1}> Whole capsule ({0})</label>",
+            escape_html(&host_scope), if checked { " checked" } else { "" }
+        );
+        if path_scope != host_scope {
+            html.push_str(&format!(
+                "<label><input type=\"radio\" name=\"scope\" value=\"{0}\"> Only {0}</label>",
+                escape_html(&path_scope)
+            ));
+        }
+        html
+    };
+    let hidden_back = format!("<input type=\"hidden\" name=\"back\" value=\"{}\">", escape_html(back));
+    let mut html = format!(
+        "<h2>Create a new identity</h2>\
+        <form method=\"post\" action=\"/.dioscuri/identity\">\
+        <input type=\"hidden\" name=\"action\" value=\"create\">{}\
+        <label>Name <input type=\"text\" name=\"name\" pattern=\"[A-Za-z0-9_\\-]+\" maxlength=\"64\" required></label>\
+        {}<button type=\"submit\">Create identity</button></form>",
+        hidden_back, scope_choice(true)
+    );
+    let identities = identity::identity_list();
+    if !identities.is_empty() {
+        let options: String = identities.iter()
+            .map(|id| format!("<option value=\"{0}\">{0}</option>", escape_html(&id.name)))
+            .collect();
+        html.push_str(&format!(
+            "<h2>Use an existing identity</h2>\
+            <form method=\"post\" action=\"/.dioscuri/identity\">\
+            <input type=\"hidden\" name=\"action\" value=\"use\">{}\
+            <label>Identity <select name=\"name\">{}</select></label>\
+            {}<button type=\"submit\">Use identity</button></form>",
+            hidden_back, options, scope_choice(true)
+        ));
     }
+    html
+}
+
+#[derive(Deserialize)]
+struct IdentityAction {
+    action: String,
+    name: String,
+    scope: String,
+    back: String,
+}
+
+/// Creates an identity or scopes an existing one, then returns the user to the page that asked for it.
+/// Like post_tofu, only forms on Dioscuri's own pages may do so, as identities are presented to capsules without asking.
+async fn post_identity(headers: HeaderMap, Form(form): Form<IdentityAction>) -> Response {
+    if !is_same_origin_strict(&headers) {
+        return (http::StatusCode::FORBIDDEN, "Identities can only be changed from Dioscuri itself").into_response();
+    }
+    let result = match form.action.as_str() {
+        "create" => identity::identity_create(&form.name, &form.scope),
+        "use" => identity::identity_add_scope(&form.name, &form.scope),
+        _ => Err("Unknown action".to_string()),
+    };
+    match result {
+        Ok(()) => Redirect::to(proxy_back_path(&form.back)).into_response(),
+        Err(e) => (http::StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+/// Returns back if it is a path on this proxy, else "/". Used to avoid redirecting users off-site.
+fn proxy_back_path(back: &str) -> &str {
+    if back.starts_with('/') && !back.starts_with("//") {
+        back
+    } else {
+        "/"
+    }
+}
+
+#[derive(Deserialize)]
+struct TofuDecision {
+    key: String,
+    fingerprint: String,
+    back: String,
+    action: String,
+}
+
+/// Applies the user's decision on a certificate that failed TOFU verification.
+/// Trusting returns the user to the page they were visiting, rejecting returns them home.
+/// Only forms on Dioscuri's own pages may decide, otherwise any site could have a certificate trusted.
+async fn post_tofu(headers: HeaderMap, Form(decision): Form<TofuDecision>) -> Response {
+    if !is_same_origin_strict(&headers) {
+        return (http::StatusCode::FORBIDDEN, "Certificates can only be trusted from Dioscuri itself").into_response();
+    }
+    let back = proxy_back_path(&decision.back);
+    match decision.action.as_str() {
+        "once" if tofu::tofu_trust_once(&decision.key, &decision.fingerprint) => Redirect::to(back).into_response(),
+        "always" if tofu::tofu_trust_permanently(&decision.key, &decision.fingerprint) => Redirect::to(back).into_response(),
+        "reject" => {
+            tofu::tofu_reject(&decision.key);
+            Redirect::to("/").into_response()
+        },
+        _ => (http::StatusCode::CONFLICT, "The certificate changed or was already decided on. Reload the page and try again.").into_response(),
+    }
+}
+
+/// Shows the configuration as a form that posts to /.dioscuri/settings.
+/// The form holds the config file, without the command line flags of this run.
+async fn get_settings(headers: HeaderMap) -> Response {
+    render_page(&headers, &settings_html(&settings_fields(&config::config_get_file()), None), "", "")
+}
+
+/// Validates and saves the settings form. On failure the form is shown again with the submitted values and the error.
+async fn post_settings(headers: HeaderMap, Form(form): Form<HashMap<String, String>>) -> Response {
+    if !is_same_origin_strict(&headers) {
+        return (http::StatusCode::FORBIDDEN, "Settings can only be changed from Dioscuri itself").into_response();
+    }
+    let html = match settings_from_form(&form).and_then(config::config_save) {
+        Ok(()) => settings_html(
+            &settings_fields(&config::config_get_file()),
+            Some("Settings saved. Network and storage changes apply after restarting Dioscuri."),
+        ),
+        Err(e) => settings_html(&form, Some(&format!("Settings not saved: {}", e))),
+    };
+    render_page(&headers, &html, "", "")
+}
+
+/// Lists the installed themes with buttons to use one in this browser or make it the default for all browsers
+async fn get_themes(headers: HeaderMap) -> Response {
+    let active = theme::theme_active(get_cookie(&headers, theme::THEME_COOKIE));
+    let configured = config::config_get().theme.name.clone();
+    let mut html = format!(
+        "<h1>Themes</h1><p>Themes are installed in <code>{}</code>, one directory per theme. \
+        The default theme is <code>{}</code>, or the built in modern theme while that is empty. \
+        Run <code>dioscuri theme install {{name}}</code> to copy a built in theme there and customise it.</p>\
+        <table><tr><th>Theme</th><th></th><th></th></tr>",
+        escape_html(&theme::theme_get_themes_dir().display().to_string()),
+        escape_html(&theme::theme_get_default_dir().display().to_string()),
+    );
+    let installed = theme::theme_list_installed();
+    let names = std::iter::once(None).chain(theme::theme_list().into_iter().map(Some));
+    for name in names {
+        let label = name.as_deref().unwrap_or("(default)");
+        let mut notes = Vec::new();
+        if let Some(name) = &name {
+            match (installed.contains(name), theme::theme_embedded(name).is_some()) {
+                (true, true) => notes.push("installed, built in files fill the gaps"),
+                (true, false) => notes.push("installed"),
+                _ => notes.push("built in"),
+            }
+        }
+        if name == active.name {
+            notes.push("in use");
+        }
+        if name == configured {
+            notes.push("used by all browsers");
+        }
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td><form method=\"post\" action=\"/.dioscuri/themes\">\
+            <input type=\"hidden\" name=\"name\" value=\"{}\">\
+            <button type=\"submit\" name=\"action\" value=\"browser\">Use in this browser</button>\
+            <button type=\"submit\" name=\"action\" value=\"default\">Use in all browsers</button></form></td></tr>",
+            escape_html(label), notes.join(", "), escape_html(name.as_deref().unwrap_or(""))
+        ));
+    }
+    html.push_str("</table>");
+    render_page(&headers, &html, "", "")
+}
+
+#[derive(Deserialize)]
+struct ThemeChoice {
+    /// Empty for the default theme
+    name: String,
+    action: String,
+}
+
+/// Switches theme for this browser with a cookie, or for all browsers in the config.
+/// Picking a theme for this browser overrides the one for all browsers, picking the default theme clears the cookie.
+async fn post_themes(headers: HeaderMap, Form(choice): Form<ThemeChoice>) -> Response {
+    if !is_same_origin_strict(&headers) {
+        return (http::StatusCode::FORBIDDEN, "Themes can only be changed from Dioscuri itself").into_response();
+    }
+    let name = Some(choice.name).filter(|name| !name.is_empty());
+    if name.as_deref().is_some_and(|name| theme::theme_get(name).is_none()) {
+        return (http::StatusCode::NOT_FOUND, "No such theme").into_response();
+    }
+    let mut response = Redirect::to("/.dioscuri/themes").into_response();
+    match choice.action.as_str() {
+        "browser" => {
+            let cookie = match &name {
+                Some(name) => format!("{}={}; Path=/; Max-Age=31536000; SameSite=Lax", theme::THEME_COOKIE, name),
+                None => format!("{}=; Path=/; Max-Age=0; SameSite=Lax", theme::THEME_COOKIE),
+            };
+            if let Ok(value) = http::HeaderValue::from_str(&cookie) {
+                response.headers_mut().insert(http::header::SET_COOKIE, value);
+            }
+        },
+        "default" => {
+            let mut config = (*config::config_get_file()).clone();
+            config.theme.name = name;
+            if let Err(e) = config::config_save(config) {
+                return (http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+            }
+        },
+        _ => return (http::StatusCode::BAD_REQUEST, "Unknown action").into_response(),
+    }
+    response
+}
+
+/// Lists the visited pages matching the search in q, grouped by host
+async fn get_history(headers: HeaderMap, Query(params): Query<HashMap<String, String>>) -> Response {
+    let query = params.get("q").map(|q| q.as_str()).unwrap_or("");
+    render_page(&headers, &history_html(&history::history_search(query), query), "", "")
+}
+
+/// Renders the history page. entries are most recent first, hosts are ordered by their most recent visit.
+fn history_html(entries: &[history::HistoryEntry], query: &str) -> String {
+    let mut html = format!(
+        "<h1>History</h1>\
+        <form method=\"get\" action=\"/.dioscuri/history\"><label>Search <input type=\"search\" name=\"q\" value=\"{}\"></label> \
+        <input type=\"submit\" value=\"Search\"></form>",
+        escape_html(query)
+    );
+    if entries.is_empty() {
+        html.push_str(if query.trim().is_empty() { "<p>No pages visited yet.</p>" } else { "<p>No visited pages match this search.</p>" });
+        return html;
+    }
+    let mut hosts: Vec<(&str, Vec<&history::HistoryEntry>)> = Vec::new();
+    for entry in entries {
+        match hosts.iter_mut().find(|(host, _)| *host == entry.host()) {
+            Some((_, group)) => group.push(entry),
+            None => hosts.push((entry.host(), vec![entry])),
+        }
+    }
+    for (host, group) in hosts {
+        html.push_str(&format!(
+            "<section class=\"dioscuri-history-host\"><h2>{0}</h2>\
+            <form method=\"post\" action=\"/.dioscuri/history\"><input type=\"hidden\" name=\"host\" value=\"{0}\">\
+            <button type=\"submit\" name=\"action\" value=\"delete_host\">Delete all from {0}</button></form><ul>",
+            escape_html(host)
+        ));
+        for entry in group {
+            let visited = std::time::UNIX_EPOCH + std::time::Duration::from_secs(entry.visited.max(0) as u64);
+            html.push_str(&format!(
+                "<li><a href=\"/{0}\">{1}</a> <code>{0}</code> status {2}, {3} \
+                <form method=\"post\" action=\"/.dioscuri/history\"><input type=\"hidden\" name=\"url\" value=\"{0}\">\
+                <button type=\"submit\" name=\"action\" value=\"delete\">Delete</button></form></li>",
+                escape_html(&entry.url), escape_html(entry.title.as_deref().unwrap_or(&entry.url)),
+                entry.status, httpdate::fmt_http_date(visited)
+            ));
+        }
+        html.push_str("</ul></section>");
+    }
+    html.push_str(
+        "<form method=\"post\" action=\"/.dioscuri/history\">\
+        <button type=\"submit\" name=\"action\" value=\"clear\">Clear all history</button></form>"
+    );
+    html
+}
+
+#[derive(Deserialize)]
+struct HistoryAction {
+    action: String,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    host: String,
+}
+
+/// Deletes a page, every page of a host, or the whole history, then shows the history again
+async fn post_history(headers: HeaderMap, Form(form): Form<HistoryAction>) -> Response {
+    if !is_same_origin_strict(&headers) {
+        return (http::StatusCode::FORBIDDEN, "History can only be changed from Dioscuri itself").into_response();
+    }
+    let result = match form.action.as_str() {
+        "delete" => history::history_delete(&form.url),
+        "delete_host" => history::history_delete_host(&form.host),
+        "clear" => history::history_clear(),
+        _ => return (http::StatusCode::BAD_REQUEST, "Unknown action").into_response(),
+    };
+    match result {
+        Ok(()) => Redirect::to("/.dioscuri/history").into_response(),
+        Err(e) => (http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+/// Returns false if the request comes from a page on another site, e.g. a form posting to this proxy.
+/// Requests without Origin or Referer (curl, scripts) are allowed.
+fn is_same_origin(headers: &HeaderMap) -> bool {
+    request_origin_matches(headers).unwrap_or(true)
+}
+
+/// Like is_same_origin, but requests without Origin or Referer are refused too.
+/// Used where a forged request does lasting harm, such as trusting a certificate or changing settings.
+fn is_same_origin_strict(headers: &HeaderMap) -> bool {
+    request_origin_matches(headers).unwrap_or(false)
+}
 
-    // read and serve the resource
-    match fs::read(&resource_path_canon) {
-        Ok(contents) => {
-            Response::builder()
-                .status(http::StatusCode::OK)
-                .body(Body::from(contents))
-                .unwrap()
+/// Returns whether the Origin, or else the Referer, of a request is this proxy, or None if it has neither
+fn request_origin_matches(headers: &HeaderMap) -> Option<bool> {
+    let Some(host) = headers.get(http::header::HOST).and_then(|h| h.to_str().ok()) else {
+        return Some(false);
+    };
+    let origin = format!("http://{}", host);
+    if let Some(value) = headers.get(http::header::ORIGIN) {
+        return Some(value.to_str().is_ok_and(|value| value == origin));
+    }
+    let value = headers.get(http::header::REFERER)?;
+    Some(value.to_str().is_ok_and(|value| value.starts_with(&format!("{}/", origin))))
+}
+
+/// Flattens config into the settings form fields. Unset optional values are empty strings.
+fn settings_fields(config: &Config) -> HashMap<String, String> {
+    let path = |path: &Option<PathBuf>| path.as_ref().map(|p| p.display().to_string()).unwrap_or_default();
+    let mut fields = HashMap::from([
+        ("network.listen_address", config.network.listen_address.to_string()),
+        ("network.listen_port", config.network.listen_port.to_string()),
+        ("network.token", config.network.token.clone().unwrap_or_default()),
+        ("network.gemini_port", config.network.gemini_port.to_string()),
+        ("network.max_redirects", config.network.max_redirects.to_string()),
+        ("network.raw_query_hosts", config.network.raw_query_hosts.join(", ")),
+        ("network.connect_timeout", config.network.connect_timeout.to_string()),
+        ("network.handshake_timeout", config.network.handshake_timeout.to_string()),
+        ("network.header_timeout", config.network.header_timeout.to_string()),
+        ("network.body_timeout", config.network.body_timeout.to_string()),
+        ("network.max_response_size", config.network.max_response_size.to_string()),
+        ("rendering.homepage", config.rendering.homepage.clone().unwrap_or_default()),
+        ("rendering.fallback_charset", config.rendering.fallback_charset.clone()),
+        ("theme.name", config.theme.name.clone().unwrap_or_default()),
+        ("theme.directory", path(&config.theme.directory)),
+        ("tofu.known_hosts", path(&config.tofu.known_hosts)),
+        ("storage.directory", path(&config.storage.directory)),
+    ].map(|(k, v)| (k.to_string(), v)));
+    if config.rendering.multiline_input {
+        fields.insert("rendering.multiline_input".to_string(), "on".to_string());
+    }
+    if config.tofu.check_expiry {
+        fields.insert("tofu.check_expiry".to_string(), "on".to_string());
+    }
+    fields
+}
+
+/// Builds the settings form from its field values, with an optional message above it
+fn settings_html(fields: &HashMap<String, String>, message: Option<&str>) -> String {
+    let text = |name: &str, label: &str| format!(
+        "<p><label>{} <input type=\"text\" name=\"{}\" value=\"{}\"></label></p>",
+        label, name, escape_html(fields.get(name).map(|v| v.as_str()).unwrap_or(""))
+    );
+    let mut html = String::from("<h1>Settings</h1>");
+    if let Some(message) = message {
+        html.push_str(&format!("<p><strong>{}</strong></p>", escape_html(message)));
+    }
+    html.push_str(&format!(
+        "<p>Saved to <code>{}</code>. Leave an optional field empty to use its default.</p>\
+        <form method=\"post\" action=\"/.dioscuri/settings\">",
+        escape_html(&config::config_path().display().to_string())
+    ));
+    html.push_str("<h2>Network</h2><p>The listen address, port and token apply after a restart.</p>");
+    html.push_str(&text("network.listen_address", "Listen address"));
+    html.push_str(&text("network.listen_port", "Listen port"));
+    html.push_str(&text("network.token", "Token (optional)"));
+    html.push_str(&text("network.gemini_port", "Default gemini port"));
+    html.push_str(&text("network.max_redirects", "Maximum redirects"));
+    html.push_str(&text("network.raw_query_hosts", "Hosts that get input unencoded, comma separated (optional)"));
+    html.push_str(&text("network.connect_timeout", "Seconds to wait for a capsule to accept the connection"));
+    html.push_str(&text("network.handshake_timeout", "Seconds to wait for the TLS handshake"));
+    html.push_str(&text("network.header_timeout", "Seconds to wait for a capsule to answer"));
+    html.push_str(&text("network.body_timeout", "Longest pause in seconds while a page downloads"));
+    html.push_str(&text("network.max_response_size", "Largest response in bytes, longer pages and downloads are cut off"));
+    html.push_str("<h2>Rendering</h2>");
+    html.push_str(&text("rendering.homepage", "Homepage, a gemini url (optional)"));
+    html.push_str(&text("rendering.fallback_charset", "Fallback charset"));
+    html.push_str(&format!(
+        "<p><label><input type=\"checkbox\" name=\"rendering.multiline_input\" value=\"on\"{}> Answer input prompts in a multi-line text box</label></p>",
+        if fields.contains_key("rendering.multiline_input") { " checked" } else { "" }
+    ));
+    html.push_str("<h2>Theme</h2>");
+    html.push_str("<p>Installed themes are listed on <a href=\"/.dioscuri/themes\">the themes page</a>.</p>");
+    html.push_str(&text("theme.name", "Theme for all browsers (optional)"));
+    html.push_str(&text("theme.directory", "Themes directory (optional)"));
+    html.push_str("<h2>Certificates</h2>");
+    html.push_str(&text("tofu.known_hosts", "known_hosts file, applies after a restart (optional)"));
+    html.push_str(&format!(
+        "<p><label><input type=\"checkbox\" name=\"tofu.check_expiry\" value=\"on\"{}> Warn about expired certificates</label></p>",
+        if fields.contains_key("tofu.check_expiry") { " checked" } else { "" }
+    ));
+    html.push_str("<h2>Storage</h2><p>Changes apply after a restart.</p>");
+    html.push_str(&text("storage.directory", "Data directory (optional)"));
+    html.push_str("<p><button type=\"submit\">Save</button></p></form>");
+    html
+}
+
+/// Reads the settings form into a config, failing on the first field that cannot be parsed.
+/// The result still has to be validated, which config::config_save does.
+fn settings_from_form(form: &HashMap<String, String>) -> Result<Config, String> {
+    let field = |name: &str| form.get(name).map(|v| v.trim()).unwrap_or("");
+    let optional = |name: &str| Some(field(name)).filter(|v| !v.is_empty()).map(|v| v.to_string());
+    fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+        value.parse().map_err(|_| format!("{}: {:?} is not a valid value", name, value))
+    }
+    let mut config = Config::default();
+    config.network.listen_address = parse("network.listen_address", field("network.listen_address"))?;
+    config.network.listen_port = parse("network.listen_port", field("network.listen_port"))?;
+    config.network.token = optional("network.token");
+    config.network.gemini_port = parse("network.gemini_port", field("network.gemini_port"))?;
+    config.network.max_redirects = parse("network.max_redirects", field("network.max_redirects"))?;
+    config.network.raw_query_hosts = field("network.raw_query_hosts").split(',')
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .collect();
+    config.network.connect_timeout = parse("network.connect_timeout", field("network.connect_timeout"))?;
+    config.network.handshake_timeout = parse("network.handshake_timeout", field("network.handshake_timeout"))?;
+    config.network.header_timeout = parse("network.header_timeout", field("network.header_timeout"))?;
+    config.network.body_timeout = parse("network.body_timeout", field("network.body_timeout"))?;
+    config.network.max_response_size = parse("network.max_response_size", field("network.max_response_size"))?;
+    config.rendering.homepage = optional("rendering.homepage");
+    config.rendering.fallback_charset = parse("rendering.fallback_charset", field("rendering.fallback_charset"))?;
+    config.rendering.multiline_input = form.contains_key("rendering.multiline_input");
+    config.theme.name = optional("theme.name");
+    config.theme.directory = optional("theme.directory").map(PathBuf::from);
+    config.tofu.known_hosts = optional("tofu.known_hosts").map(PathBuf::from);
+    config.tofu.check_expiry = form.contains_key("tofu.check_expiry");
+    config.storage.directory = optional("storage.directory").map(PathBuf::from);
+    Ok(config)
+}
+
+/// Serves a non-gemtext body untouched with the Content-Type taken from its media type.
+/// Media types that cannot be represented as a header value are served as application/octet-stream.
+/// The body comes from the capsule, so it is sandboxed: html or svg served by a capsule cannot run scripts on this origin.
+fn passthrough_response(media: &MediaType, body: Body) -> Response {
+    let content_type = http::HeaderValue::from_str(&media.to_string())
+        .unwrap_or_else(|_| http::HeaderValue::from_static("application/octet-stream"));
+    Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .header(http::header::CONTENT_SECURITY_POLICY, PASSTHROUGH_POLICY)
+        .header(http::header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(body)
+        .unwrap()
+}
+
+/// Pipes a body to the browser as it arrives. It is only read as fast as the browser takes it.
+/// If the body fails midway, the transfer is aborted so the browser does not take a truncated file for a complete one.
+fn passthrough_body(body: GeminiBody) -> Body {
+    let bytes = stream::unfold(Some(body), |body| async move {
+        let mut body = body?;
+        match body.chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(body))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
         }
-        Err(_) => (http::StatusCode::NOT_FOUND, "File not found").into_response(),
+    });
+    Body::from_stream(bytes)
+}
+
+/// Searches the active theme for {my_path_to_file} by extracting my_path_to_file
+/// The filepath must only exist within the theme's folder for security concerns, see ActiveTheme::read
+async fn get_resource(Path(filepath): Path<String>, headers: HeaderMap) -> impl IntoResponse {
+    // read and serve the resource from the active theme, falling back to its built in files
+    match get_theme(&headers).read(&filepath) {
+        Ok(file) => resource::resource_response(&filepath, file, &headers),
+        Err(ThemeFileError::Forbidden) => (http::StatusCode::FORBIDDEN, "Access denied").into_response(),
+        Err(ThemeFileError::NotFound) => (http::StatusCode::NOT_FOUND, "File not found").into_response(),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::browser::strip_first_url_query_key;
+    use crate::browser::*;
+
+    #[test]
+    fn test_parse_proxy_query(){
+        assert_eq!(parse_proxy_query("dioscuri_input=hello+world%21"), ProxyQuery::Input("hello world!".to_string()));
+        assert_eq!(parse_proxy_query("dioscuri_input=a%3Db%26c%2Bd"), ProxyQuery::Input("a=b&c+d".to_string()));
+        assert_eq!(parse_proxy_query("dioscuri_input="), ProxyQuery::Input("".to_string()));
+        // queries from links are part of the gemini url and are not split on '='
+        assert_eq!(parse_proxy_query("q=Your%20input"), ProxyQuery::Raw("q=Your%20input".to_string()));
+        assert_eq!(parse_proxy_query("search"), ProxyQuery::Raw("search".to_string()));
+        assert_eq!(parse_proxy_query("a=b&dioscuri_input=c"), ProxyQuery::Raw("a=b&dioscuri_input=c".to_string()));
+    }
+
+    #[test]
+    fn test_location_path(){
+        // raw queries, e.g. from raw_query_hosts or the retry after a 59, keep their spaces in the response url
+        let location = location_path("example.org:1966/s?a b");
+        assert_eq!(location, "/example.org:1966/s?a%20b");
+        // the browser asks for the same path, so the stashed response is found
+        let uri: Uri = location.parse().unwrap();
+        assert_eq!(uri.path_and_query().unwrap().as_str(), location);
+        assert!(http::HeaderValue::from_str(&location).is_ok());
+        assert_eq!(location_path("example.org/q?caf\u{e9} \"x\"#1"), "/example.org/q?caf%C3%A9%20%22x%22%231");
+        assert_eq!(location_path("example.org/search?a%20b"), "/example.org/search?a%20b");
+    }
+
+    #[test]
+    fn test_page_policy(){
+        let mut headers = HeaderMap::new();
+        headers.insert(http::header::HOST, http::HeaderValue::from_static("localhost:1965"));
+        let response = with_page_policy(Html("").into_response(), &headers);
+        let policy = response.headers()[http::header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+        assert!(policy.contains("script-src http://localhost:1965/.src/ http://localhost:1965/.dioscuri/input.js;"));
+        assert_eq!(response.headers()[http::header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+
+        // a host that could smuggle in extra sources falls back to no scripts at all
+        headers.insert(http::header::HOST, http::HeaderValue::from_static("evil.example 'unsafe-inline'"));
+        let response = with_page_policy(Html("").into_response(), &headers);
+        let policy = response.headers()[http::header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+        assert!(policy.contains("script-src 'none';"));
+    }
+
+    #[test]
+    fn test_passthrough_is_sandboxed(){
+        let response = passthrough_response(&MediaType::parse("text/html"), Body::from("<script>alert(1)</script>"));
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "text/html");
+        assert!(response.headers()[http::header::CONTENT_SECURITY_POLICY].to_str().unwrap().starts_with("sandbox;"));
+    }
+
+    #[test]
+    fn test_split_token_param(){
+        assert_eq!(split_token_param("dioscuri_token=abc"), (Some("abc".to_string()), None));
+        assert_eq!(split_token_param("query=x&dioscuri_token=abc"), (Some("abc".to_string()), Some("query=x".to_string())));
+        assert_eq!(split_token_param("query=x"), (None, Some("query=x".to_string())));
+        assert!(token_matches("secret-token", "secret-token"));
+        assert!(!token_matches("secret-tokem", "secret-token"));
+        assert!(!token_matches("secret", "secret-token"));
+    }
+
+    #[test]
+    fn test_settings_form_round_trip(){
+        let mut config = Config::default();
+        config.network.token = Some("s3cret-token".to_string());
+        config.rendering.homepage = Some("geminiprotocol.net/".to_string());
+        config.tofu.check_expiry = false;
+        config.rendering.multiline_input = true;
+        config.network.header_timeout = 5;
+        assert_eq!(settings_from_form(&settings_fields(&config)), Ok(config));
+
+        let mut fields = settings_fields(&Config::default());
+        fields.insert("network.listen_port".to_string(), "http".to_string());
+        assert!(settings_from_form(&fields).is_err());
+    }
+
+    #[test]
+    fn test_same_origin(){
+        let mut headers = HeaderMap::new();
+        headers.insert(http::header::HOST, http::HeaderValue::from_static("127.0.0.1:1965"));
+        assert!(is_same_origin(&headers));
+        assert!(!is_same_origin_strict(&headers));
+        headers.insert(http::header::REFERER, http::HeaderValue::from_static("http://127.0.0.1:1965/.dioscuri/settings"));
+        assert!(is_same_origin(&headers));
+        assert!(is_same_origin_strict(&headers));
+        headers.insert(http::header::ORIGIN, http::HeaderValue::from_static("http://evil.example"));
+        assert!(!is_same_origin(&headers));
+        assert!(!is_same_origin_strict(&headers));
+    }
+
+    #[test]
+    fn test_http_status_for(){
+        assert_eq!(http_status_for(&StatusCode::from(20)), http::StatusCode::OK);
+        assert_eq!(http_status_for(&StatusCode::from(10)), http::StatusCode::OK);
+        assert_eq!(http_status_for(&StatusCode::from(51)), http::StatusCode::NOT_FOUND);
+        assert_eq!(http_status_for(&StatusCode::from(52)), http::StatusCode::GONE);
+        assert_eq!(http_status_for(&StatusCode::from(59)), http::StatusCode::BAD_REQUEST);
+        assert_eq!(http_status_for(&StatusCode::from(42)), http::StatusCode::BAD_GATEWAY);
+        assert_eq!(http_status_for(&StatusCode::from(53)), http::StatusCode::BAD_GATEWAY);
+        assert_eq!(http_status_for(&StatusCode::from(47)), http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(http_status_for(&StatusCode::from(60)), http::StatusCode::UNAUTHORIZED);
+        assert_eq!(http_status_for(&StatusCode::StatusUnknown), http::StatusCode::BAD_GATEWAY);
+
+        let response = with_gemini_status(Html("").into_response(), &StatusCode::from(44), "30");
+        assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "30");
+        let response = with_gemini_status(Html("").into_response(), &StatusCode::from(44), "soon");
+        assert!(response.headers().get(http::header::RETRY_AFTER).is_none());
+    }
 
     #[test]
-    fn test_strip_first_url_query_key(){
-        let in0 = "hello_world".to_string();
-        let out0 = "hello_world"; // no '='
-        assert_eq!(strip_first_url_query_key(in0), out0);
+    fn test_error_template_names(){
+        assert_eq!(error_template_names(Some(51)), vec!["error-51.html", "error-5x.html", "error.html"]);
+        assert_eq!(error_template_names(Some(61)), vec!["error-61.html", "error-6x.html", "error-cert.html", "error.html"]);
+        assert_eq!(error_template_names(None), vec!["error-client.html", "error.html"]);
+    }
+
+    #[test]
+    fn test_error_html(){
+        let dir = std::env::temp_dir().join(format!("dioscuri-error-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let theme = ActiveTheme { name: None, dir: dir.clone(), embedded: None };
+        let page = ErrorPage { code: Some(51), status: StatusCode::from(51).as_str(), meta: "No <such> page", url: "error.test/a", back: "/error.test/a" };
+
+        // the default template fills in every component
+        let html = error_html(&theme, &page, "");
+        assert!(html.contains("data-status=\"51\""));
+        assert!(html.contains("<span class=\"dioscuri-error-code\">51</span> Not Found"));
+        assert!(html.contains("No &lt;such&gt; page"));
+        assert!(html.contains("gemini://error.test/a"));
+        assert!(html.contains("href=\"/error.test/a\">Retry</a>"));
+        assert!(!html.contains("View cached copy"));
+        assert!(!html.contains("<Dioscuri"));
 
-        let in1 = "foo=bar".to_string();
-        let out1 = "bar";
-        assert_eq!(strip_first_url_query_key(in1), out1);
+        // the most specific template wins
+        std::fs::write(dir.join("error.html"), "any <DioscuriErrorCode/>").unwrap();
+        assert_eq!(error_html(&theme, &page, ""), "any 51");
+        std::fs::write(dir.join("error-5x.html"), "5x <DioscuriErrorMeta/> <DioscuriErrorDetails/>").unwrap();
+        assert_eq!(error_html(&theme, &page, "<b>details</b>"), "5x No &lt;such&gt; page <b>details</b>");
+        let client = ErrorPage { code: None, status: "Host Not Found", ..page };
+        assert_eq!(error_html(&theme, &client, ""), "any ");
 
-        let in2 = "bar=baz&x=1&y=2".to_string();
-        let out2 = "baz&x=1&y=2";
-        assert_eq!(strip_first_url_query_key(in2), out2);
+        // a cached copy is linked once the page was fetched
+        std::fs::write(dir.join("error.html"), "<DioscuriErrorLinks/>").unwrap();
+        cache::cache_store("error.test/a", "text/gemini", b"# a");
+        assert!(error_html(&theme, &client, "").contains("href=\"/.dioscuri/cached/error.test/a\">View cached copy</a>"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-        let in3 = "q=my query&q2=my second query".to_string();
-        let out3 = "my query&q2=my second query";
-        assert_eq!(strip_first_url_query_key(in3), out3);
+    #[test]
+    fn test_sensitive_input(){
+        let html = inject_sensitive_input("<Dioscuri/><DioscuriPrompt/><DioscuriInput/>", "Password", HTML_SENSITIVE_INPUT);
+        assert_eq!(html, format!("Password{HTML_SENSITIVE_INPUT}"));
+        assert!(html.contains("type=\"password\"") && html.contains("autocomplete=\"off\"") && html.contains("method=\"post\""));
+        let html = inject_sensitive_input("<DioscuriSensitiveInput/>|<DioscuriInput/>", "", HTML_SENSITIVE_INPUT);
+        assert_eq!(html, format!("{HTML_SENSITIVE_INPUT}|"));
 
-        let in4 = "onlykey=".to_string();
-        let out4 = "";
-        assert_eq!(strip_first_url_query_key(in4), out4);
+        let response = with_sensitive_policy(Html("").into_response());
+        assert_eq!(response.headers()[http::header::CACHE_CONTROL], "no-store");
+        assert_eq!(response.headers()[http::header::REFERRER_POLICY], "no-referrer");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_input_html(){
+        let dir = std::env::temp_dir().join(format!("dioscuri-input-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let theme = ActiveTheme { name: None, dir: dir.clone(), embedded: None };
+        assert_eq!(input_max_bytes("input.test/a"), 1024 - "gemini://input.test/a?".len());
+        assert_eq!(input_max_bytes(&"a".repeat(2000)), 0);
+
+        let html = input_html(&theme, "input.test/a", false);
+        assert!(html.contains("<input type=\"text\" name=\"dioscuri_input\">"));
+        assert!(html.contains(&format!("data-max-bytes=\"{}\"", 1024 - "gemini://input.test/a?".len())));
+        assert!(input_html(&theme, "input.test/a", true).contains("<textarea name=\"dioscuri_input\""));
+
+        std::fs::write(dir.join("input.html"), "<input name=\"dioscuri_input\" data-max=\"<DioscuriInputMaxBytes/>\">").unwrap();
+        assert_eq!(input_html(&theme, "x", false), "<input name=\"dioscuri_input\" data-max=\"1013\">");
+        std::fs::write(dir.join("input-multiline.html"), "<textarea name=\"dioscuri_input\"></textarea>").unwrap();
+        assert_eq!(input_html(&theme, "x", true), "<textarea name=\"dioscuri_input\"></textarea>");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_history_html(){
+        let entry = |url: &str, title: Option<&str>, visited| history::HistoryEntry {
+            url: url.to_string(), title: title.map(|t| t.to_string()), visited, status: 20,
+        };
+        let entries = [
+            entry("b.test/new", None, 300),
+            entry("a.test/", Some("<A>"), 200),
+            entry("b.test/old", Some("Old"), 100),
+        ];
+        let html = history_html(&entries, "\"q");
+        assert!(html.contains("value=\"&quot;q\""));
+        // hosts in order of their latest visit, each listed once
+        let b = html.find("<h2>b.test</h2>").unwrap();
+        let a = html.find("<h2>a.test</h2>").unwrap();
+        assert!(b < a && html.find("b.test/old").unwrap() < a);
+        assert_eq!(html.matches("<h2>").count(), 2);
+        assert!(html.contains("<a href=\"/b.test/new\">b.test/new</a>"));
+        assert!(html.contains("<a href=\"/a.test/\">&lt;A&gt;</a>"));
+        assert!(html.contains("value=\"clear\""));
+        assert!(history_html(&[], "").contains("No pages visited yet."));
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::gemini::GeminiStream;
+
+// The cache module keeps the last successful gemtext response of recently visited pages in memory.
+// Error pages link to the cached copy, so a page can still be read while its capsule is down.
+// It also holds responses fetched after a redirect until the browser follows Dioscuri to their url.
+// Their bodies are not read yet, so the connection to the capsule stays open until then.
+// Nothing is written to disk, the cache is empty after a restart.
+
+/// How many pages are kept. The least recently stored page is dropped first.
+const CACHE_CAPACITY: usize = 64;
+/// Bodies larger than this are not cached
+pub const CACHE_MAX_BODY: usize = 512 * 1024;
+
+/// A successful response kept for url
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedPage {
+    pub url: String,
+    /// The META of the response, i.e. its media type
+    pub meta: String,
+    pub body: Vec<u8>,
+    pub stored: SystemTime,
+}
+
+static CACHE: OnceLock<Mutex<VecDeque<CachedPage>>> = OnceLock::new();
+
+/// How long a redirected response waits for the browser to ask for it
+const REDIRECTED_TTL: Duration = Duration::from_secs(30);
+
+/// A response fetched after a redirect
+struct Redirected {
+    stored: Instant,
+    response: GeminiStream,
+}
+
+/// Responses fetched after a redirect, by the proxy path the browser was sent to
+static REDIRECTED: OnceLock<Mutex<HashMap<String, Redirected>>> = OnceLock::new();
+
+fn _cache_pages() -> &'static Mutex<VecDeque<CachedPage>> {
+    CACHE.get_or_init(|| Mutex::new(VecDeque::new()))
+}
+
+/// Keeps a copy of a successful response for url, replacing any older copy
+pub fn cache_store(url: &str, meta: &str, body: &[u8]) {
+    if body.len() > CACHE_MAX_BODY {
+        return;
+    }
+    let mut pages = _cache_pages().lock().unwrap_or_else(|e| e.into_inner());
+    pages.retain(|page| page.url != url);
+    if pages.len() >= CACHE_CAPACITY {
+        pages.pop_front();
+    }
+    pages.push_back(CachedPage {
+        url: url.to_string(),
+        meta: meta.to_string(),
+        body: body.to_vec(),
+        stored: SystemTime::now(),
+    });
+}
+
+/// Returns the cached copy of url, if any
+pub fn cache_get(url: &str) -> Option<CachedPage> {
+    let pages = _cache_pages().lock().unwrap_or_else(|e| e.into_inner());
+    pages.iter().find(|page| page.url == url).cloned()
+}
+
+/// Keeps the response for the page at path, the proxy path the browser is being redirected to.
+/// It is handed out once by cache_take_redirected, so the capsule is not asked twice for the same page.
+pub fn cache_stash_redirected(path: &str, response: GeminiStream) {
+    let mut stashed = REDIRECTED.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap_or_else(|e| e.into_inner());
+    stashed.retain(|_, redirected| redirected.stored.elapsed() < REDIRECTED_TTL);
+    stashed.insert(path.to_string(), Redirected { stored: Instant::now(), response });
+}
+
+/// Takes the response kept for path by cache_stash_redirected, unless it expired.
+/// Every page load asks, so expired responses are dropped here too, closing their connections
+/// even if the browser never follows its redirect and nothing new is stashed.
+pub fn cache_take_redirected(path: &str) -> Option<GeminiStream> {
+    let mut stashed = REDIRECTED.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap_or_else(|e| e.into_inner());
+    stashed.retain(|_, redirected| redirected.stored.elapsed() < REDIRECTED_TTL);
+    stashed.remove(path).map(|redirected| redirected.response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gemini::{GeminiResponse, StatusCode};
+
+    #[test]
+    fn test_cache() {
+        cache_store("cache.test/a", "text/gemini", b"# old");
+        cache_store("cache.test/a", "text/gemini", b"# new");
+        assert_eq!(cache_get("cache.test/a").unwrap().body, b"# new".to_vec());
+        assert_eq!(cache_get("cache.test/missing"), None);
+        cache_store("cache.test/big", "text/gemini", &vec![b'a'; CACHE_MAX_BODY + 1]);
+        assert_eq!(cache_get("cache.test/big"), None);
+        for i in 0..CACHE_CAPACITY {
+            cache_store(&format!("cache.test/{i}"), "text/gemini", b"");
+        }
+        assert_eq!(cache_get("cache.test/a"), None);
+        assert!(cache_get("cache.test/0").is_some());
+    }
+
+    #[test]
+    fn test_redirected() {
+        let response = GeminiResponse {
+            status: StatusCode::Success,
+            raw_code: 20,
+            meta: "text/gemini".to_string(),
+            body: b"# r".to_vec(),
+            url: "gemini://cache.test/r".to_string(),
+            peer_cert: Vec::new(),
+        };
+        cache_stash_redirected("/cache.test/r", response.into());
+        assert!(cache_take_redirected("/cache.test/other").is_none());
+        assert_eq!(cache_take_redirected("/cache.test/r").map(|stashed| stashed.url), Some("gemini://cache.test/r".to_string()));
+        // handed out only once
+        assert!(cache_take_redirected("/cache.test/r").is_none());
+    }
+}
@@ -0,0 +1,508 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{gemini::DEFAULT_PORT, theme};
+
+// The config module reads ~/.dioscuri/config.toml and the command line flags.
+// Flags override the file, the file overrides the defaults.
+// The loaded configuration is process wide, see config_get.
+// The file is kept apart from the flags, so saving settings never writes a flag given for one run, see config_get_file.
+
+const CONFIG_FILENAME: &str = "config.toml";
+const DEFAULT_LISTEN_PORT: u16 = 1965;
+const DEFAULT_MAX_REDIRECTS: u8 = 5;
+/// More redirects than this are almost certainly a loop
+const MAX_REDIRECTS_LIMIT: u8 = 20;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+const DEFAULT_HEADER_TIMEOUT: u64 = 30;
+const DEFAULT_BODY_TIMEOUT: u64 = 120;
+/// Timeouts are at most an hour, a capsule that is slower than that is not coming back
+const MAX_TIMEOUT: u64 = 60 * 60;
+const DEFAULT_MAX_RESPONSE_SIZE: u64 = 32 * 1024 * 1024;
+/// A response header alone may be up to 1029 bytes
+const MIN_RESPONSE_SIZE: u64 = 1024 * 2;
+
+pub const USAGE: &str = "Usage: dioscuri [options]
+       dioscuri [options] theme list
+       dioscuri [options] theme install <name> [--force]
+
+Commands:
+  theme list                 List the built in and installed themes
+  theme install <name>       Copy a built in theme to ~/.dioscuri/themes/<name> to customise it.
+                             --force overwrites the files of an existing copy
+
+Options:
+  --config <path>    Read the configuration from path instead of ~/.dioscuri/config.toml
+  --address <ip>     Address the proxy listens on (default 127.0.0.1)
+  --port <port>      Port the proxy listens on (default 1965)
+  --token <secret>   Require this token before serving any page
+  -h, --help         Show this message";
+
+/// Everything Dioscuri can be configured with.
+/// Every field has a default, so an empty file is a valid configuration.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub rendering: RenderingConfig,
+    pub theme: ThemeConfig,
+    pub tofu: TofuConfig,
+    pub storage: StorageConfig,
+}
+
+/// Where the http proxy listens, who may use it and how gemini requests are made
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub listen_address: IpAddr,
+    pub listen_port: u16,
+    /// When set, browsers must visit /?dioscuri_token={token} once before any page is served
+    pub token: Option<String>,
+    /// Port used for gemini urls that do not name one
+    pub gemini_port: u16,
+    /// How many redirects are followed before giving up
+    pub max_redirects: u8,
+    /// Hosts that get user input in queries as typed instead of percent encoded, for servers that do not decode them
+    pub raw_query_hosts: Vec<String>,
+    /// Seconds to wait for the host name to resolve and the capsule to accept the connection
+    pub connect_timeout: u64,
+    /// Seconds to wait for the TLS handshake
+    pub handshake_timeout: u64,
+    /// Seconds to wait for the response header once the request is sent
+    pub header_timeout: u64,
+    /// Seconds the whole body may take to arrive. Pages streamed to the browser may take longer,
+    /// as long as no pause is longer than this.
+    pub body_timeout: u64,
+    /// The most bytes a response, header included, may have. Pages streamed to the browser are not held in memory
+    /// and may be larger.
+    pub max_response_size: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            listen_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            listen_port: DEFAULT_LISTEN_PORT,
+            token: None,
+            gemini_port: DEFAULT_PORT,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            raw_query_hosts: Vec::new(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            header_timeout: DEFAULT_HEADER_TIMEOUT,
+            body_timeout: DEFAULT_BODY_TIMEOUT,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.listen_address, self.listen_port)
+    }
+}
+
+/// How pages are shown
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderingConfig {
+    /// A gemini url opened instead of home.html, e.g. geminiprotocol.net/
+    pub homepage: Option<String>,
+    /// Charset for text responses that do not name one. The gemini specification says utf-8.
+    pub fallback_charset: String,
+    /// Answer input prompts in a multi-line text box instead of a single line
+    pub multiline_input: bool,
+}
+
+impl Default for RenderingConfig {
+    fn default() -> Self {
+        RenderingConfig { homepage: None, fallback_charset: "utf-8".to_string(), multiline_input: false }
+    }
+}
+
+/// Which theme the interface uses, see the theme module
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    /// The theme used by browsers that did not pick one. Unset means {storage.directory}/browser.
+    pub name: Option<String>,
+    /// Where themes are installed, defaults to {storage.directory}/themes
+    pub directory: Option<PathBuf>,
+}
+
+/// How capsule certificates are trusted
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TofuConfig {
+    /// Defaults to {storage.directory}/known_hosts
+    pub known_hosts: Option<PathBuf>,
+    /// Warn about expired or not yet valid certificates. Many capsules never renew theirs,
+    /// so an expired certificate can be trusted permanently from the warning.
+    pub check_expiry: bool,
+}
+
+impl Default for TofuConfig {
+    fn default() -> Self {
+        TofuConfig { known_hosts: None, check_expiry: true }
+    }
+}
+
+/// Where Dioscuri keeps its data
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Defaults to ~/.dioscuri
+    pub directory: Option<PathBuf>,
+}
+
+impl Config {
+    /// Checks the values serde cannot check by itself
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(token) = &self.network.token {
+            if token.len() < 8 {
+                return Err("network.token must be at least 8 characters long".to_string());
+            }
+            if !token.chars().all(|c| c.is_ascii_alphanumeric() || "-_.~".contains(c)) {
+                return Err("network.token may only contain letters, digits and -_.~".to_string());
+            }
+        }
+        if self.network.listen_port == 0 {
+            return Err("network.listen_port must not be 0".to_string());
+        }
+        if self.network.gemini_port == 0 {
+            return Err("network.gemini_port must not be 0".to_string());
+        }
+        if self.network.max_redirects > MAX_REDIRECTS_LIMIT {
+            return Err(format!("network.max_redirects must be at most {}", MAX_REDIRECTS_LIMIT));
+        }
+        if let Some(host) = self.network.raw_query_hosts.iter()
+            .find(|host| host.is_empty() || host.contains(|c: char| c.is_whitespace() || "/?#:".contains(c))) {
+            return Err(format!("network.raw_query_hosts: {:?} is not a host name", host));
+        }
+        let timeouts = [
+            ("network.connect_timeout", self.network.connect_timeout),
+            ("network.handshake_timeout", self.network.handshake_timeout),
+            ("network.header_timeout", self.network.header_timeout),
+            ("network.body_timeout", self.network.body_timeout),
+        ];
+        for (name, seconds) in timeouts {
+            if !(1..=MAX_TIMEOUT).contains(&seconds) {
+                return Err(format!("{} must be between 1 and {} seconds", name, MAX_TIMEOUT));
+            }
+        }
+        if self.network.max_response_size < MIN_RESPONSE_SIZE {
+            return Err(format!("network.max_response_size must be at least {} bytes", MIN_RESPONSE_SIZE));
+        }
+        if let Some(homepage) = &self.rendering.homepage {
+            let stripped = homepage.strip_prefix("gemini://").unwrap_or(homepage);
+            if stripped.contains("://") || Url::parse(&format!("gemini://{}", stripped)).is_err() {
+                return Err(format!("rendering.homepage: {} is not a gemini url", homepage));
+            }
+        }
+        if self.theme.name.as_ref().is_some_and(|name| !theme::theme_valid_name(name)) {
+            return Err("theme.name may only contain letters, digits, '-' and '_'".to_string());
+        }
+        if encoding_rs::Encoding::for_label(self.rendering.fallback_charset.as_bytes()).is_none() {
+            return Err(format!("rendering.fallback_charset: unknown charset {}", self.rendering.fallback_charset));
+        }
+        let paths = [
+            ("theme.directory", &self.theme.directory),
+            ("tofu.known_hosts", &self.tofu.known_hosts),
+            ("storage.directory", &self.storage.directory),
+        ];
+        for (name, path) in paths {
+            if path.as_ref().is_some_and(|path| !path.is_absolute()) {
+                return Err(format!("{} must be an absolute path", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// The directory all data lives in, ~/.dioscuri unless configured
+    pub fn storage_dir(&self) -> PathBuf {
+        self.storage.directory.clone().unwrap_or_else(|| dirs::home_dir().unwrap().join(".dioscuri"))
+    }
+
+    /// The directory themes are installed in
+    pub fn themes_dir(&self) -> PathBuf {
+        self.theme.directory.clone().unwrap_or_else(|| self.storage_dir().join("themes"))
+    }
+
+    pub fn identities_dir(&self) -> PathBuf {
+        self.storage_dir().join("identities")
+    }
+}
+
+/// Settings given as command line flags. They apply to this run only and are never written to the config file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigOverrides {
+    pub listen_address: Option<IpAddr>,
+    pub listen_port: Option<u16>,
+    pub token: Option<String>,
+}
+
+impl ConfigOverrides {
+    /// Returns config with the flags that were given replacing its settings
+    pub fn apply(&self, mut config: Config) -> Config {
+        if let Some(address) = self.listen_address {
+            config.network.listen_address = address;
+        }
+        if let Some(port) = self.listen_port {
+            config.network.listen_port = port;
+        }
+        if self.token.is_some() {
+            config.network.token = self.token.clone();
+        }
+        config
+    }
+}
+
+struct ConfigState {
+    path: PathBuf,
+    /// As in the config file, which is what config_save writes
+    file: Arc<Config>,
+    overrides: ConfigOverrides,
+    /// file with overrides applied
+    config: Arc<Config>,
+}
+
+fn state() -> &'static Mutex<ConfigState> {
+    static STATE: OnceLock<Mutex<ConfigState>> = OnceLock::new();
+    STATE.get_or_init(|| {
+        let config = Arc::new(Config::default());
+        Mutex::new(ConfigState { path: config_default_path(), file: config.clone(), overrides: ConfigOverrides::default(), config })
+    })
+}
+
+/// Makes config, as read from the config file at path, the process wide configuration. path is where config_save writes to.
+pub fn config_init(path: PathBuf, config: Config) {
+    let config = Arc::new(config);
+    *state().lock().unwrap() = ConfigState { path, file: config.clone(), overrides: ConfigOverrides::default(), config };
+}
+
+/// Applies overrides on top of the config file for the rest of this run, see config_init
+pub fn config_override(overrides: ConfigOverrides) {
+    let mut state = state().lock().unwrap();
+    state.config = Arc::new(overrides.apply((*state.file).clone()));
+    state.overrides = overrides;
+}
+
+/// Returns the process wide configuration, or the defaults if config_init was not called
+pub fn config_get() -> Arc<Config> {
+    state().lock().unwrap().config.clone()
+}
+
+/// Returns the configuration as in the config file, without the command line overrides.
+/// This is what the settings pages show and change, so flags given for one run are not saved.
+pub fn config_get_file() -> Arc<Config> {
+    state().lock().unwrap().file.clone()
+}
+
+/// Returns the file the configuration was loaded from
+pub fn config_path() -> PathBuf {
+    state().lock().unwrap().path.clone()
+}
+
+/// Validates config, writes it to the config file and makes it the process wide configuration,
+/// with the command line overrides still applied. config should start from config_get_file.
+/// Network and storage settings are only read at startup, so they apply after a restart.
+pub fn config_save(config: Config) -> Result<(), String> {
+    config.validate()?;
+    let contents = toml::to_string(&config).map_err(|e| format!("Could not serialize the configuration: {e}"))?;
+    let mut state = state().lock().unwrap();
+    let applied = state.overrides.apply(config.clone());
+    applied.validate()?;
+    if let Some(parent) = state.path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Could not create {:?}: {e}", parent))?;
+    }
+    let tmp = state.path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp, contents)
+        .and_then(|_| fs::rename(&tmp, &state.path))
+        .map_err(|e| format!("Could not write {:?}: {e}", state.path))?;
+    state.file = Arc::new(config);
+    state.config = Arc::new(applied);
+    Ok(())
+}
+
+/// What the command line asked for
+#[derive(Debug, PartialEq)]
+pub enum CliAction {
+    /// Start the proxy with config, which was loaded from path, and the overrides given as flags
+    Run { config: Box<Config>, overrides: ConfigOverrides, path: PathBuf },
+    ThemeList { config: Box<Config>, overrides: ConfigOverrides, path: PathBuf },
+    ThemeInstall { config: Box<Config>, overrides: ConfigOverrides, path: PathBuf, name: String, force: bool },
+    Help,
+}
+
+/// Reads the config file and the flags overriding it from the command line arguments (without the program name).
+/// A missing config file is fine, an unreadable or invalid one is an error, as is a file the flags make invalid.
+pub fn config_from_args(args: impl IntoIterator<Item = String>) -> Result<CliAction, String> {
+    let mut path = None;
+    let mut overrides = ConfigOverrides::default();
+    let mut force = false;
+    let mut command = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().ok_or_else(|| format!("{} needs a value", flag));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(CliAction::Help),
+            "--config" => path = Some(PathBuf::from(value("--config")?)),
+            "--address" => {
+                let raw = value("--address")?;
+                overrides.listen_address = Some(raw.parse::<IpAddr>().map_err(|_| format!("--address: {} is not an IP address", raw))?);
+            },
+            "--port" => {
+                let raw = value("--port")?;
+                overrides.listen_port = Some(raw.parse::<u16>().map_err(|_| format!("--port: {} is not a port number", raw))?);
+            },
+            "--token" => overrides.token = Some(value("--token")?),
+            "--force" => force = true,
+            _ if !arg.starts_with('-') => command.push(arg),
+            _ => return Err(format!("Unknown option {}\n\n{}", arg, USAGE)),
+        }
+    }
+
+    let path = path.unwrap_or_else(config_default_path);
+    let config = config_load(&path)?;
+    overrides.apply(config.clone()).validate()?;
+    let config = Box::new(config);
+    let command: Vec<&str> = command.iter().map(|arg| arg.as_str()).collect();
+    match command[..] {
+        [] => Ok(CliAction::Run { config, overrides, path }),
+        ["theme", "list"] => Ok(CliAction::ThemeList { config, overrides, path }),
+        ["theme", "install", name] => Ok(CliAction::ThemeInstall { config, overrides, path, name: name.to_string(), force }),
+        _ => Err(format!("Unknown command {}\n\n{}", command.join(" "), USAGE)),
+    }
+}
+
+/// Reads a config file. A missing file gives the default configuration.
+pub fn config_load(path: &Path) -> Result<Config, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => config_parse(&contents).map_err(|e| format!("Invalid config file {:?}: {}", path, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(format!("Could not read config file {:?}: {}", path, e)),
+    }
+}
+
+/// Parses and validates the contents of a config file
+pub fn config_parse(contents: &str) -> Result<Config, String> {
+    let config: Config = toml::from_str(contents).map_err(|e| e.message().to_string())?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Returns ~/.dioscuri/config.toml
+pub fn config_default_path() -> PathBuf {
+    let home = dirs::home_dir().unwrap();
+    home.join(".dioscuri").join(CONFIG_FILENAME)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = config_parse("").unwrap();
+        assert_eq!(config.network.socket_addr(), "127.0.0.1:1965".parse().unwrap());
+
+        let config = config_parse("[network]\nlisten_address = \"0.0.0.0\"\nlisten_port = 8080\ntoken = \"s3cret-token\"").unwrap();
+        assert_eq!(config.network.socket_addr(), "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.network.token.as_deref(), Some("s3cret-token"));
+
+        assert!(config_parse("[network]\nlisten_address = \"localhost\"").is_err());
+        assert!(config_parse("[network]\nlisten_port = 70000").is_err());
+        assert!(config_parse("[network]\nport = 1965").is_err());
+        assert!(config_parse("[network]\ntoken = \"short\"").is_err());
+        assert!(config_parse("[network]\ntoken = \"has spaces in it\"").is_err());
+        assert!(config_parse("[network]\nmax_redirects = 50").is_err());
+        assert!(config_parse("[network]\nraw_query_hosts = [\"lazy.example\"]").is_ok());
+        assert!(config_parse("[network]\nraw_query_hosts = [\"lazy.example/path\"]").is_err());
+        let config = config_parse("[network]\nconnect_timeout = 3\nbody_timeout = 600\nmax_response_size = 1048576").unwrap();
+        assert_eq!((config.network.connect_timeout, config.network.body_timeout), (3, 600));
+        assert_eq!(config.network.max_response_size, 1048576);
+        assert!(config_parse("[network]\nheader_timeout = 0").is_err());
+        assert!(config_parse("[network]\nhandshake_timeout = 86400").is_err());
+        assert!(config_parse("[network]\nmax_response_size = 100").is_err());
+    }
+
+    #[test]
+    fn test_parse_sections() {
+        let config = config_parse("[rendering]\nhomepage = \"gemini://geminiprotocol.net/\"\nfallback_charset = \"latin1\"\n\
+            [theme]\nname = \"modern\"\ndirectory = \"/srv/themes\"\n[tofu]\ncheck_expiry = false\n[storage]\ndirectory = \"/srv/dioscuri\"").unwrap();
+        assert_eq!(config.rendering.homepage.as_deref(), Some("gemini://geminiprotocol.net/"));
+        assert_eq!(config.theme.name.as_deref(), Some("modern"));
+        assert_eq!(config.themes_dir(), PathBuf::from("/srv/themes"));
+        assert_eq!(config.identities_dir(), PathBuf::from("/srv/dioscuri/identities"));
+        assert!(!config.tofu.check_expiry);
+
+        assert!(config_parse("[rendering]\nfallback_charset = \"klingon\"").is_err());
+        assert!(config_parse("[rendering]\nhomepage = \"https://example.com/\"").is_err());
+        assert!(config_parse("[storage]\ndirectory = \"relative/dir\"").is_err());
+        assert!(config_parse("[theme]\nname = \"../browser\"").is_err());
+        assert!(config_parse("[tofu]\ncheck_expiry = \"yes\"").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut config = Config::default();
+        config.network.token = Some("s3cret-token".to_string());
+        config.theme.directory = Some(PathBuf::from("/srv/theme"));
+        let serialized = toml::to_string(&config).unwrap();
+        assert_eq!(config_parse(&serialized), Ok(config));
+    }
+
+    #[test]
+    fn test_args_override_file() {
+        let dir = std::env::temp_dir().join(format!("dioscuri-config-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(CONFIG_FILENAME);
+        fs::write(&path, "[network]\nlisten_port = 8080\ntoken = \"from-the-file\"").unwrap();
+
+        let Ok(CliAction::Run { config, overrides, .. }) = config_from_args(args(&["--config", path.to_str().unwrap(), "--address", "::1"])) else {
+            panic!("config should load");
+        };
+        let applied = overrides.apply((*config).clone());
+        assert_eq!(applied.network.socket_addr(), "[::1]:8080".parse().unwrap());
+        assert_eq!(applied.network.token.as_deref(), Some("from-the-file"));
+
+        let Ok(CliAction::Run { config, overrides, .. }) = config_from_args(args(&["--config", path.to_str().unwrap(), "--port", "1966", "--token", "from-the-flag"])) else {
+            panic!("config should load");
+        };
+        let applied = overrides.apply((*config).clone());
+        assert_eq!(applied.network.listen_port, 1966);
+        assert_eq!(applied.network.token.as_deref(), Some("from-the-flag"));
+        // the config as in the file keeps its own values
+        assert_eq!(config.network.listen_port, 8080);
+        assert_eq!(config.network.token.as_deref(), Some("from-the-file"));
+        assert!(config_from_args(args(&["--config", path.to_str().unwrap(), "--token", "short"])).is_err());
+
+        let Ok(CliAction::ThemeInstall { name, force, .. }) = config_from_args(args(&["--config", path.to_str().unwrap(), "theme", "install", "nyan", "--force"])) else {
+            panic!("theme install should parse");
+        };
+        assert_eq!((name.as_str(), force), ("nyan", true));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bad_args() {
+        assert_eq!(config_from_args(args(&["--help"])), Ok(CliAction::Help));
+        assert!(config_from_args(args(&["--port"])).is_err());
+        assert!(config_from_args(args(&["--port", "http"])).is_err());
+        assert!(config_from_args(args(&["--address", "localhost"])).is_err());
+        assert!(config_from_args(args(&["--verbose"])).is_err());
+        assert!(config_from_args(args(&["theme"])).is_err());
+        assert!(config_from_args(args(&["theme", "install"])).is_err());
+    }
+}
@@ -1,16 +1,27 @@
-use std::{io::{Read, Write}, net::TcpStream};
+//! This file implements the Gemini protocol
+//! It takes in a url/uri and returns either a GeminiResponse or a GeminiError.
 
-use native_tls::{TlsConnector, TlsStream};
-use url::{form_urlencoded, Url};
+use std::fmt;
+use std::time::Duration;
 
-use crate::tofu;
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWriteExt}, net::TcpStream, time::{timeout, timeout_at, Instant}};
+use tokio_native_tls::{TlsConnector, TlsStream};
+use url::Url;
 
-/// This file implements the Gemini protocol
-/// It takes in a url/uri and returns either (data, status code) or an error string.
+use crate::{config::{self, Config}, identity, tofu::{self, TofuError}};
 
-const CRLF: &str = "\r\n";
+const CRLF: &[u8] = b"\r\n";
+/// How much is read from a capsule at once
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+const DEFAULT_MIME: &str = "text/gemini";
+pub const DEFAULT_PORT: u16 = 1965;
 
-#[derive(Debug, PartialEq)] // allow debug and comparisons
+/// Returns the port used for urls that do not name one, network.gemini_port in the config
+pub fn gemini_default_port() -> u16 {
+    config::config_get().network.gemini_port
+}
+
+#[derive(Debug, Clone, PartialEq)] // allow debug and comparisons
 pub enum StatusCode {
     InputExpected,
     InputSensitive,
@@ -31,8 +42,6 @@ pub enum StatusCode {
     FailureCertUnauthorized,
     FailureCertInvalid,
     StatusUnknown,
-    FailureClient,
-    ResponseError,
 }
 
 impl StatusCode {
@@ -57,8 +66,6 @@ impl StatusCode {
             StatusCode::FailureCertUnauthorized => "Client Certificate Unauthorized",
             StatusCode::FailureCertInvalid => "Client Certificate Invalid",
             StatusCode::StatusUnknown => "Unknown Status",
-            StatusCode::FailureClient => "Client Failure",
-            StatusCode::ResponseError => "Response Error",
         }
     }
 }
@@ -94,14 +101,294 @@ impl From<i32> for StatusCode {
     }
 }
 
+/// A response from a capsule
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeminiResponse {
+    pub status: StatusCode,
+    /// The two digit code as sent by the capsule, e.g. 12 for a response whose status is InputExpected
+    pub raw_code: u8,
+    /// The text after the code: a prompt for 1x, the media type for 2x, the target for 3x, an error message otherwise
+    pub meta: String,
+    /// The raw body, only sent with 2x. Use MediaType::parse on meta to interpret it.
+    pub body: Vec<u8>,
+    /// The gemini:// url the response belongs to, after following redirects
+    pub url: String,
+    /// The certificate presented by the capsule, DER encoded
+    pub peer_cert: Vec<u8>,
+}
+
+/// A response whose body is read as it arrives, see GeminiClient::get_stream
+#[derive(Debug)]
+pub struct GeminiStream {
+    pub status: StatusCode,
+    /// The two digit code as sent by the capsule
+    pub raw_code: u8,
+    /// The text after the code, see GeminiResponse::meta
+    pub meta: String,
+    /// The gemini:// url the response belongs to, after following redirects
+    pub url: String,
+    /// The certificate presented by the capsule, DER encoded
+    pub peer_cert: Vec<u8>,
+    pub body: GeminiBody,
+}
+
+impl GeminiStream {
+    /// Reads the rest of the body into a GeminiResponse.
+    /// The body must arrive within body_timeout, and the response must fit in max_response_size.
+    pub async fn into_response(mut self) -> Result<GeminiResponse, GeminiError> {
+        let deadline = self.body.deadline();
+        let mut body = Vec::new();
+        while let Some(chunk) = self.body.read(deadline).await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(GeminiResponse {
+            status: self.status,
+            raw_code: self.raw_code,
+            meta: self.meta,
+            body,
+            url: self.url,
+            peer_cert: self.peer_cert,
+        })
+    }
+}
+
+/// Streams an already read response, for tests of code that takes a GeminiStream
+#[cfg(test)]
+impl From<GeminiResponse> for GeminiStream {
+    fn from(response: GeminiResponse) -> Self {
+        GeminiStream {
+            status: response.status,
+            raw_code: response.raw_code,
+            meta: response.meta,
+            url: response.url,
+            peer_cert: response.peer_cert,
+            body: GeminiBody {
+                reader: None,
+                pending: response.body,
+                received: 0,
+                timeout: Duration::MAX,
+                max_size: u64::MAX,
+            },
+        }
+    }
+}
+
+/// The body of a streamed response, still coming from the capsule.
+/// Nothing is read until chunk is called, so a slow reader slows the capsule down instead of filling memory.
+/// Dropping it closes the connection.
+pub struct GeminiBody {
+    reader: Option<Box<dyn AsyncRead + Send + Unpin>>,
+    /// Body bytes that arrived along with the header
+    pending: Vec<u8>,
+    /// Bytes received so far, header included
+    received: u64,
+    /// body_timeout of the client
+    timeout: Duration,
+    /// max_response_size of the client
+    max_size: u64,
+}
+
+impl fmt::Debug for GeminiBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeminiBody")
+            .field("open", &self.reader.is_some())
+            .field("pending", &self.pending.len())
+            .field("received", &self.received)
+            .finish()
+    }
+}
+
+impl GeminiBody {
+    /// Returns the next part of the body as it arrives, or None once the capsule closed the connection.
+    /// There is no limit on how long the body takes, only a pause longer than body_timeout fails, with a body timeout.
+    /// The response may not grow past max_response_size though, so endless bodies end with TooLarge.
+    pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>, GeminiError> {
+        let deadline = self.deadline();
+        self.read(deadline).await
+    }
+
+    /// Reads the next part of the body, failing with a body timeout if nothing arrives before deadline
+    /// and with TooLarge once the response is larger than max_size
+    async fn read(&mut self, deadline: Instant) -> Result<Option<Vec<u8>>, GeminiError> {
+        if !self.pending.is_empty() {
+            return Ok(Some(std::mem::take(&mut self.pending)));
+        }
+        let Some(reader) = self.reader.as_mut() else {
+            return Ok(None);
+        };
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+        let read = timeout_at(deadline, reader.read(&mut chunk)).await
+            .map_err(|_| GeminiError::Timeout { stage: TimeoutStage::Body, after: self.timeout })?
+            .map_err(|e| GeminiError::Tcp(format!("Error while reading from TLS stream!\n{}", e)))?;
+        if read == 0 {
+            self.reader = None;
+            return Ok(None);
+        }
+        chunk.truncate(read);
+        self.received += read as u64;
+        if self.received > self.max_size {
+            self.reader = None;
+            return Err(GeminiError::TooLarge { limit: self.max_size });
+        }
+        Ok(Some(chunk))
+    }
+
+    /// Returns when body_timeout from now is up. Bodies that were already read never time out.
+    fn deadline(&self) -> Instant {
+        Instant::now().checked_add(self.timeout)
+            .unwrap_or_else(|| Instant::now() + Duration::from_secs(60 * 60 * 24 * 365))
+    }
+}
+
+/// Why no response could be read from a capsule
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeminiError {
+    /// The host name could not be resolved
+    Dns(String),
+    /// No connection could be made, or it broke while talking to the capsule
+    Tcp(String),
+    /// The TLS handshake failed, or the capsule presented no certificate
+    Tls(String),
+    /// The capsule's certificate is not trusted, see the tofu module
+    Tofu(TofuError),
+    /// A step of the request took longer than its timeout
+    Timeout { stage: TimeoutStage, after: Duration },
+    /// The response is larger than the limit, in bytes
+    TooLarge { limit: u64 },
+    /// The capsule's answer is not a valid gemini response, or its redirects could not be followed
+    Protocol(String),
+}
+
+/// The steps of a request that have their own timeout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutStage {
+    /// Resolving the host name and opening the TCP connection
+    Connect,
+    /// The TLS handshake
+    Handshake,
+    /// Waiting for the response header after sending the request
+    Header,
+    /// Reading the whole body
+    Body,
+}
+
+impl GeminiError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GeminiError::Dns(_) => "Host Not Found",
+            GeminiError::Tcp(_) => "Connection Failed",
+            GeminiError::Tls(_) => "TLS Failure",
+            GeminiError::Tofu(_) => "Certificate Not Trusted",
+            GeminiError::Timeout { stage: TimeoutStage::Connect, .. } => "Connection Timed Out",
+            GeminiError::Timeout { stage: TimeoutStage::Handshake, .. } => "Handshake Timed Out",
+            GeminiError::Timeout { stage: TimeoutStage::Header, .. } => "Response Timed Out",
+            GeminiError::Timeout { stage: TimeoutStage::Body, .. } => "Download Timed Out",
+            GeminiError::TooLarge { .. } => "Response Too Large",
+            GeminiError::Protocol(_) => "Invalid Response",
+        }
+    }
+}
+
+impl std::error::Error for GeminiError {}
+
+impl fmt::Display for GeminiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeminiError::Dns(e) | GeminiError::Tcp(e) | GeminiError::Tls(e) | GeminiError::Protocol(e) => write!(f, "{}", e),
+            GeminiError::Timeout { stage, after } => {
+                let step = match stage {
+                    TimeoutStage::Connect => "The capsule did not accept the connection",
+                    TimeoutStage::Handshake => "The TLS handshake did not finish",
+                    TimeoutStage::Header => "The capsule did not answer",
+                    TimeoutStage::Body => "The page did not finish downloading",
+                };
+                write!(f, "{} within {} seconds", step, after.as_secs())
+            },
+            GeminiError::TooLarge { limit } => write!(f, "The response is larger than {} bytes", limit),
+            GeminiError::Tofu(TofuError::Mismatch { key, .. }) => write!(f, "The certificate of {} changed", key),
+            GeminiError::Tofu(TofuError::Expired { key, .. }) => write!(f, "The certificate of {} is expired", key),
+            GeminiError::Tofu(TofuError::Invalid(e)) => write!(f, "Certificate verification failed: {}", e),
+        }
+    }
+}
+
+/// A media type parsed from the META of a 2x response, e.g. `text/gemini; charset=utf-8; lang=en`
+/// The mime is lowercased; parameter keys are lowercased and values are kept as-is.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MediaType {
+    pub mime: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl MediaType {
+    /// Parses a META string into a media type.
+    /// An empty or malformed mime falls back to text/gemini as per the gemini specification.
+    pub fn parse(meta: &str) -> MediaType {
+        let mut parts = meta.split(';');
+        let mime = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let mime = match mime.split_once('/') {
+            Some((t, st)) if !t.is_empty() && !st.is_empty() && !mime.contains(char::is_whitespace) => mime,
+            _ => DEFAULT_MIME.to_string(),
+        };
+        let params = parts
+            .filter_map(|param| param.split_once('='))
+            .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().trim_matches('"').to_string()))
+            .filter(|(k, _)| !k.is_empty())
+            .collect();
+        MediaType { mime, params }
+    }
+
+    /// Returns the value of the first parameter named key, if any
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// True if the body should be rendered as gemtext
+    pub fn is_gemtext(&self) -> bool {
+        self.mime == DEFAULT_MIME
+    }
+
+    /// Decodes a text body using the charset parameter, defaulting to rendering.fallback_charset (utf-8).
+    /// Unknown charsets and invalid sequences are decoded lossily instead of failing.
+    pub fn decode_text(&self, body: &[u8]) -> String {
+        let (text, _, _) = self.encoding().decode(body);
+        text.into_owned()
+    }
+
+    /// Returns a decoder for a text body that arrives in pieces, with the same charset as decode_text
+    pub fn decoder(&self) -> encoding_rs::Decoder {
+        self.encoding().new_decoder()
+    }
+
+    fn encoding(&self) -> &'static encoding_rs::Encoding {
+        let fallback = config::config_get().rendering.fallback_charset.clone();
+        self.param("charset")
+            .or(Some(fallback.as_str()))
+            .and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()))
+            .unwrap_or(encoding_rs::UTF_8)
+    }
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mime)?;
+        for (k, v) in &self.params {
+            write!(f, "; {}={}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
 
 /// Given a url of format(s):
 /// 1. {protocol}://address/*
 /// 2. address/*
 /// 
 /// return only the 'address/*' part
-fn _strip_protocol_from_url(url: &String) -> String {
-    let mut temp_url = url.clone();
+fn _strip_protocol_from_url(url: &str) -> String {
+    let mut temp_url = url.to_string();
     // 1. Check if the URL starts with a protocol (e.g., "http://", "gemini://").
     // If it does, remove the protocol and the "://" part.
     if let Some(protocol_end_idx) = temp_url.find("://") {
@@ -115,7 +402,7 @@ fn _strip_protocol_from_url(url: &String) -> String {
 /// 2. address/* 
 ///  
 /// Extract 'address' and return it as a String
-fn _extract_address_from_url(url: &String) -> String {
+fn _extract_address_from_url(url: &str) -> String {
     let temp_url = _strip_protocol_from_url(url);
 
     // Find the index of the first slash '/' in the remaining string.
@@ -128,45 +415,121 @@ fn _extract_address_from_url(url: &String) -> String {
     }
 }
 
-/// Given a string of format:
-/// {foo}/{bar}/{baz}
-/// urlencode baz and return 
-/// {foo}/{bar}/{baz'}, where baz' is the urlencoded slug
-/// if baz contains a query (i.e. ?), preserve the first ? and urlencode the rest of the input
-fn encode_url_suffix(path_str: String) -> String {
-    let (prefix_slice, baz_slice) = if let Some(last_slash_idx) = path_str.rfind('/') {
-        // If a slash is found, split into the part before it and the part after it.
-        // `last_slash_idx + 1` ensures we start after the slash for `baz_slice`.
-        (&path_str[..last_slash_idx], &path_str[last_slash_idx + 1..])
-    } else {
-        // If no slash, the entire string is considered the 'baz' part, with an empty prefix.
-        ("", &path_str[..])
-    };
+/// Given a url of format(s):
+/// 1. {protocol}://host:port/*
+/// 2. host:port/*
+/// 3. host/*
+/// 
+/// Extract (host, port). The port defaults to gemini_default_port() if absent or invalid.
+/// IPv6 literals keep their brackets, e.g. [::1]:1966 gives ("[::1]", 1966)
+fn _extract_host_port_from_url(url: &str) -> (String, u16) {
+    let addr = _extract_address_from_url(url);
+    // Strip any query from a bare address such as foo.net?bar
+    let addr = addr.split(['?', '#']).next().unwrap_or("").to_string();
+    // Only the colon after the host (or after the closing bracket of an IPv6 literal) denotes the port
+    let host_end = if addr.starts_with('[') { addr.find(']').map(|i| i + 1).unwrap_or(addr.len()) } else { 0 };
+    match addr[host_end..].rfind(':') {
+        Some(idx) => {
+            let idx = host_end + idx;
+            let port = addr[idx + 1..].parse::<u16>().unwrap_or(gemini_default_port());
+            (addr[..idx].to_string(), port)
+        }
+        None => (addr, gemini_default_port()),
+    }
+}
 
-    // If baz_slice contains '?', split and encode only the RHS
-    let encoded_baz = if let Some((before_q, after_q)) = baz_slice.split_once('?') {
-        let encoded_right: String = form_urlencoded::byte_serialize(after_q.as_bytes()).collect();
-        format!("{}?{}", before_q, encoded_right)
-    } else {
-        form_urlencoded::byte_serialize(baz_slice.as_bytes()).collect()
-    };
+/// How user input is written into the query of a request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryEncoding {
+    /// Percent encode every byte except the unreserved characters A-Z a-z 0-9 - . _ ~, e.g. a space becomes %20.
+    /// This is what the gemini specification asks for.
+    Percent,
+    /// Send the input as typed, for servers that do not decode their queries.
+    /// Only % CR LF and control characters are encoded, as they would corrupt the request line.
+    Raw,
+}
 
-    if prefix_slice.is_empty() {
-        encoded_baz
-    } else {
-        format!("{}/{}", prefix_slice, encoded_baz)
+/// Encodes user input for the query of a gemini request
+pub fn gemini_encode_query(input: &str, encoding: QueryEncoding) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for c in input.chars() {
+        let keep = match encoding {
+            QueryEncoding::Percent => c.is_ascii_alphanumeric() || "-._~".contains(c),
+            QueryEncoding::Raw => c != '%' && !c.is_control(),
+        };
+        if keep {
+            encoded.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
     }
+    encoded
 }
 
-/// Given a response payload, extract the response code, header message and body
-fn extract_response_header(response: String) -> (StatusCode, String, String) {
-    let (header_line, body) = match response.split_once(CRLF) {
-        Some((h, b)) => (h, b.to_string()), // Body can be multi-line
-        None => {
+/// Returns the queries to try for input, in order.
+/// Percent encoded input comes first, followed by the input as typed if that differs;
+/// hosts that get raw queries only get the input as typed.
+fn _gemini_input_queries(input: &str, encoding: QueryEncoding) -> Vec<String> {
+    let raw = gemini_encode_query(input, QueryEncoding::Raw);
+    match encoding {
+        QueryEncoding::Raw => vec![raw],
+        QueryEncoding::Percent => {
+            let encoded = gemini_encode_query(input, QueryEncoding::Percent);
+            if encoded == raw { vec![encoded] } else { vec![encoded, raw] }
+        },
+    }
+}
+
+/// Reads the response header from reader, leaving the body to be streamed.
+/// The header must arrive within header_timeout. The header line is decoded as UTF-8,
+/// the body is left untouched as it may be binary.
+async fn _gemini_read_header(client: &GeminiClient, mut reader: Box<dyn AsyncRead + Send + Unpin>, url: String, peer_cert: Vec<u8>) -> Result<GeminiStream, GeminiError> {
+    let deadline = Instant::now() + client.header_timeout;
+    let mut response = Vec::new();
+    let mut chunk = vec![0; READ_CHUNK_SIZE];
+    let mut searched = 0;
+    let crlf_idx = loop {
+        // only look at the new bytes, and the last one before them in case CR and LF were split
+        if let Some(idx) = response[searched..].windows(CRLF.len()).position(|w| w == CRLF) {
+            break searched + idx;
+        }
+        searched = response.len().saturating_sub(1);
+        let read = timeout_at(deadline, reader.read(&mut chunk)).await
+            .map_err(|_| GeminiError::Timeout { stage: TimeoutStage::Header, after: client.header_timeout })?
+            .map_err(|e| GeminiError::Tcp(format!("Error while reading from TLS stream!\n{}", e)))?;
+        if read == 0 {
             // If no CRLF, the response format is invalid.
-            return (StatusCode::ResponseError, "Response does not have CRLF!".to_string(), "".to_string());
+            return Err(GeminiError::Protocol("Response does not have CRLF!".to_string()));
         }
+        if (response.len() + read) as u64 > client.max_response_size {
+            return Err(GeminiError::TooLarge { limit: client.max_response_size });
+        }
+        response.extend_from_slice(&chunk[..read]);
     };
+    let (status, raw_code, meta) = _parse_header(&response[..crlf_idx])?;
+    let received = response.len() as u64;
+    Ok(GeminiStream {
+        status,
+        raw_code,
+        meta,
+        url,
+        peer_cert,
+        body: GeminiBody {
+            reader: Some(reader),
+            pending: response.split_off(crlf_idx + CRLF.len()), // Body can be multi-line
+            received,
+            timeout: client.body_timeout,
+            max_size: client.max_response_size,
+        },
+    })
+}
+
+/// Parses a header line, without its CRLF, into its status, the code as sent and META
+fn _parse_header(header_line: &[u8]) -> Result<(StatusCode, u8, String), GeminiError> {
+    let header_line = String::from_utf8_lossy(header_line);
 
     // Process the header line
     let (code_str, header_message_str) = match header_line.split_once(" ") {
@@ -174,27 +537,16 @@ fn extract_response_header(response: String) -> (StatusCode, String, String) {
         None => {
             // If no space is found, the entire header_line is considered the code string,
             // and the header message is empty. This handles cases like "41\r\n".
-            (header_line, "")
+            (header_line.as_ref(), "")
         }
     };
 
-    // Ensure that the status code is precisely 2 digits
-    if code_str.len() != 2 {
-        return (StatusCode::StatusUnknown, "Server returned invalid status code!".to_string(), "".to_string());
-    }
-    let code_parsed = code_str.parse::<i32>();
-    if code_parsed.is_err() {
-        // invalid parse (non numeric)
-        return (StatusCode::StatusUnknown, "Server returned invalid status code!".to_string(), "".to_string());
-    }   
-    let code_num = code_parsed.unwrap();
-    if code_num < 10 || code_num > 69 {
-        // status code not within 10 and 69 (undefined based on specs)
-        return (StatusCode::StatusUnknown, "Server returned invalid status code!".to_string(), "".to_string());
-    }
-    let status = StatusCode::from(code_num);
-
-    return (status, header_message_str.to_string(), body);
+    // Ensure that the status code is precisely 2 digits, within 10 and 69 (others are undefined based on specs)
+    let raw_code = match code_str.parse::<u8>() {
+        Ok(code) if code_str.len() == 2 && (10..=69).contains(&code) => code,
+        _ => return Err(GeminiError::Protocol("Server returned invalid status code!".to_string())),
+    };
+    Ok((StatusCode::from(raw_code as i32), raw_code, header_message_str.to_string()))
 }
 
 /// Return the uri but with \r\n appended
@@ -202,105 +554,317 @@ fn client_build_request_str(uri: String) -> String {
     format!("{}\r\n", uri)
 }
 
-/// Given a url, get the corresponding (code, header_data, data) tuple
-/// The url string can be of format: gemini://{url} or simply {url}
-/// Any client-side internal errors will be returned with the appropriate status code.
-pub fn get_gemini(url: String) -> (StatusCode, String, String){
-    // Extract out the domain/address, port and uri
-    let port = 1965;
-    let addr = _extract_address_from_url(&url);
-    let addr_port = format!("{}:{}", _extract_address_from_url(&url), port);
-    let conn_res =  TcpStream::connect(&addr_port);
-    if conn_res.is_err() {
-        return (StatusCode::FailureClient, "".to_string(), "TcpStream failed to connect".to_string())
-    }
-    
-    // All gemini communication uses TLS
-    let stream = conn_res.unwrap();    
-    let connector = TlsConnector::builder()
-    .danger_accept_invalid_certs(true)
-    .build()
-    .unwrap();
-    let mut stream = connector.connect(&addr, stream).unwrap();
-    let _ = tofu::tofu_handle_certificate(stream.peer_certificate().unwrap().unwrap());
-
-    // Ensure all strings are stripped to maintain a standard format;
-    // then urlencode the last section,
-    // then re-insert the 'gemini://' prefix
-    let url_stripped = _strip_protocol_from_url(&url);
-
-    // WARNING: Suffix encoding is omitted since many actual gemini servers are lazy and don't decode urlencoded payloads.
-    // TODO: implement a fallback
-    // let encoded_url = encode_url_suffix(url_stripped);
-    // let final_url = format!("gemini://{}", encoded_url);
-    let final_url = format!("gemini://{}", url_stripped);
-
-    let request = client_build_request_str(final_url.clone());
-    
-    if let Err(e) = stream.write_all(request.as_bytes()) {
-        return (StatusCode::FailureClient, "".to_string(), format!("Error while writing to TLS stream!\n{}", e).to_string())
-    }
-
-    let mut response = String::new();
-    if let Err(e) = stream.read_to_string(&mut response) {
-        return (StatusCode::FailureClient, "".to_string(), format!("Error while reading from TLS stream!\n{}", e).to_string())
-    }
-    // No issue with the response.
-    let (code, header, body) = extract_response_header(response);
-    if code  == StatusCode::RedirectPerm || code == StatusCode::RedirectTemp {
-        return handle_redirect(final_url, header, stream)
-    }
-    return (code, header, body);
-}
-
-/// Automatically handle redirects with depth limit
-fn handle_redirect(initial_url: String, mut redirect_uri: String, mut stream: TlsStream<TcpStream>) -> (StatusCode, String, String) {
-    let mut redirect_depth = 5;
-    let mut current_url = initial_url;
-
-    while redirect_depth > 0 {
-        // Resolve relative redirect_uri against the current_url
-        let resolved = match Url::parse(&current_url)
-            .and_then(|base| base.join(&redirect_uri)) {
-            Ok(u) => u.to_string(),
-            Err(e) => return (
-                StatusCode::FailureClient,
-                "".to_string(),
-                format!("Failed to resolve redirect: {}", e),
-            ),
-        };
+/// Sends requests to capsules.
+/// Build one with GeminiClient::builder(), or take the settings of the config with GeminiClient::from_config.
+/// Certificates are always checked against the process wide trust store, see the tofu module.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), dioscuri::gemini::GeminiError> {
+/// use dioscuri::gemini::GeminiClient;
+///
+/// let client = GeminiClient::builder().max_redirects(2).build();
+/// let response = client.get("geminiprotocol.net/").await?;
+/// println!("{} {}", response.raw_code, response.meta);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct GeminiClient {
+    max_redirects: u8,
+    raw_query_hosts: Vec<String>,
+    identities: bool,
+    connect_timeout: Duration,
+    handshake_timeout: Duration,
+    header_timeout: Duration,
+    body_timeout: Duration,
+    max_response_size: u64,
+}
+
+/// Builds a GeminiClient, starting from the default configuration
+#[derive(Debug, Clone)]
+pub struct GeminiClientBuilder {
+    client: GeminiClient,
+}
+
+impl GeminiClientBuilder {
+    /// How many redirects are followed before giving up, 0 turns them off
+    pub fn max_redirects(mut self, max_redirects: u8) -> Self {
+        self.client.max_redirects = max_redirects;
+        self
+    }
 
-        println!("Redirecting to: {}", resolved);
-        if let Err(e) = stream.write_all(resolved.as_bytes()) {
-            return (StatusCode::FailureClient, "".to_string(), format!("Error while writing to TLS stream!\n{}", e).to_string())
+    /// Hosts that get input as typed instead of percent encoded, see QueryEncoding::Raw
+    pub fn raw_query_hosts(mut self, hosts: Vec<String>) -> Self {
+        self.client.raw_query_hosts = hosts;
+        self
+    }
+
+    /// Whether client certificates from the identities directory are presented to the capsules they are scoped to
+    pub fn identities(mut self, identities: bool) -> Self {
+        self.client.identities = identities;
+        self
+    }
+
+    /// How long resolving the host name and opening the connection may take
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.client.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// How long the TLS handshake may take
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.client.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    /// How long the capsule may take to send the response header once the request is sent
+    pub fn header_timeout(mut self, header_timeout: Duration) -> Self {
+        self.client.header_timeout = header_timeout;
+        self
+    }
+
+    /// How long the whole body may take to arrive
+    pub fn body_timeout(mut self, body_timeout: Duration) -> Self {
+        self.client.body_timeout = body_timeout;
+        self
+    }
+
+    /// The most bytes a response may have, header included
+    pub fn max_response_size(mut self, max_response_size: u64) -> Self {
+        self.client.max_response_size = max_response_size;
+        self
+    }
+
+    pub fn build(self) -> GeminiClient {
+        self.client
+    }
+}
+
+impl GeminiClient {
+    pub fn builder() -> GeminiClientBuilder {
+        GeminiClientBuilder { client: GeminiClient::from_config(&Config::default()) }
+    }
+
+    /// Returns a client with the network settings of config
+    pub fn from_config(config: &Config) -> GeminiClient {
+        GeminiClient {
+            max_redirects: config.network.max_redirects,
+            raw_query_hosts: config.network.raw_query_hosts.clone(),
+            identities: true,
+            connect_timeout: Duration::from_secs(config.network.connect_timeout),
+            handshake_timeout: Duration::from_secs(config.network.handshake_timeout),
+            header_timeout: Duration::from_secs(config.network.header_timeout),
+            body_timeout: Duration::from_secs(config.network.body_timeout),
+            max_response_size: config.network.max_response_size,
         }
-        let mut response = String::new();
-        if let Err(e) = stream.read_to_string(&mut response) {
-            return (StatusCode::FailureClient, "".to_string(), format!("Error while reading from TLS stream!\n{}", e).to_string())
+    }
+
+    /// Given a url, get the response of the capsule
+    /// The url string can be of format: gemini://{url} or simply {url}
+    /// The whole body is read, see get_stream to handle it as it arrives.
+    /// Failures to get a response at all are returned as a GeminiError.
+    /// If the server's certificate fails TOFU verification, GeminiError::Tofu is returned and no request is sent.
+    /// See tofu::tofu_get_pending for the details.
+    /// 
+    /// All network I/O is async, so a slow capsule only parks this future instead of a tokio worker.
+    /// Dropping the future (e.g. axum dropping the handler when the browser tab disconnects)
+    /// drops the TLS stream with it, closing the connection to the capsule.
+    pub async fn get(&self, url: &str) -> Result<GeminiResponse, GeminiError> {
+        self.get_stream(url).await?.into_response().await
+    }
+
+    /// Like get, but returns as soon as the header arrived. The body is read from GeminiStream::body.
+    ///
+    /// Redirects are followed, up to max_redirects of them. Each hop opens a new connection, which may go to
+    /// another host or port, and has its certificate checked on its own. The url of the response is the gemini:// url
+    /// of the last request sent, i.e. the page the response belongs to.
+    pub async fn get_stream(&self, url: &str) -> Result<GeminiStream, GeminiError> {
+        let mut current_url = gemini_request_url(url);
+        let mut redirects_left = self.max_redirects;
+        loop {
+            let response = _gemini_request(self, &current_url).await?;
+            if response.status != StatusCode::RedirectPerm && response.status != StatusCode::RedirectTemp {
+                return Ok(response);
+            }
+            if redirects_left == 0 {
+                return Err(GeminiError::Protocol(format!("Too many redirects, the last one from {} to {}", current_url, response.meta)));
+            }
+            redirects_left -= 1;
+            current_url = _resolve_redirect(&current_url, &response.meta).map_err(GeminiError::Protocol)?;
         }
-        let (code, header, body) = extract_response_header(response);
-        // If it's another redirect, continue
-        if code == StatusCode::RedirectPerm || code == StatusCode::RedirectTemp {
-            current_url = resolved;
-            redirect_uri = header.clone(); // follow new redirect location
-            redirect_depth -= 1;
-            continue;
+    }
+
+    /// Sends input to url as its query, e.g. the answer to an input prompt.
+    /// The input is percent encoded as the specification asks, unless the host is one of raw_query_hosts.
+    /// Servers that do not decode queries may answer 59 Bad Request to an encoded query,
+    /// so in that case the request is sent once more with the input as typed.
+    pub async fn get_input(&self, url: &str, input: &str) -> Result<GeminiResponse, GeminiError> {
+        self.get_input_stream(url, input).await?.into_response().await
+    }
+
+    /// Like get_input, but returns as soon as the header arrived, see get_stream.
+    /// The input replaces any query url already has, and its fragment is dropped.
+    pub async fn get_input_stream(&self, url: &str, input: &str) -> Result<GeminiStream, GeminiError> {
+        let queries = _gemini_input_queries(input, self.query_encoding(url));
+        let last = queries.len() - 1;
+        for (i, query) in queries.into_iter().enumerate() {
+            let response = self.get_stream(&_gemini_with_query(url, &query)?).await;
+            let bad_request = matches!(&response, Ok(response) if response.status == StatusCode::FailureServerBadReq);
+            if !bad_request || i == last {
+                return response;
+            }
         }
+        unreachable!("there is always at least one query to try")
+    }
+
+    /// Returns how input for url is encoded: Raw for hosts in raw_query_hosts, else Percent
+    fn query_encoding(&self, url: &str) -> QueryEncoding {
+        let (host, _) = _extract_host_port_from_url(url);
+        if self.raw_query_hosts.iter().any(|raw_host| raw_host.eq_ignore_ascii_case(&host)) {
+            QueryEncoding::Raw
+        } else {
+            QueryEncoding::Percent
+        }
+    }
+}
+
+/// Gets url with the settings of the process wide configuration, see GeminiClient::get
+pub async fn get_gemini(url: String) -> Result<GeminiResponse, GeminiError> {
+    GeminiClient::from_config(&config::config_get()).get(&url).await
+}
+
+/// Sends input to url with the settings of the process wide configuration, see GeminiClient::get_input
+pub async fn get_gemini_input(url: String, input: &str) -> Result<GeminiResponse, GeminiError> {
+    GeminiClient::from_config(&config::config_get()).get_input(&url, input).await
+}
+
+/// Returns the gemini:// url requested for url, which may or may not have a protocol.
+/// Responses carry this url when no redirect was followed.
+pub fn gemini_request_url(url: &str) -> String {
+    format!("gemini://{}", _strip_protocol_from_url(url))
+}
+
+/// Returns the gemini:// url of url with its query replaced by query, an already encoded query, and without fragment.
+/// Url::set_query would encode a raw query again, so the query is appended to the cleaned url.
+fn _gemini_with_query(url: &str, query: &str) -> Result<String, GeminiError> {
+    let mut parsed = Url::parse(&gemini_request_url(url))
+        .map_err(|e| GeminiError::Protocol(format!("Invalid url {}: {}", url, e)))?;
+    parsed.set_fragment(None);
+    parsed.set_query(None);
+    Ok(format!("{parsed}?{query}"))
+}
+
+/// Resolves the target of a redirect sent by the capsule at current_url.
+/// Only gemini urls are followed.
+fn _resolve_redirect(current_url: &str, target: &str) -> Result<String, String> {
+    let resolved = Url::parse(current_url)
+        .and_then(|base| base.join(target.trim()))
+        .map_err(|e| format!("Failed to resolve redirect to {}: {}", target, e))?;
+    if resolved.scheme() != "gemini" || resolved.host_str().is_none_or(|host| host.is_empty()) {
+        return Err(format!("The capsule redirected to {}, which is not a gemini url", resolved));
+    }
+    Ok(resolved.to_string())
+}
+
+/// Sends a single request for url, a gemini:// url, on a new connection and returns the response once its header arrived.
+/// Redirects are returned as they are, see GeminiClient::get_stream.
+/// Each step is bounded by the client's timeouts.
+/// If a client certificate is scoped to url, the server is first checked on a connection without it,
+/// so a server that fails TOFU verification never sees the certificate. The certificate is then
+/// presented on a second connection, which is checked again before the request is sent.
+async fn _gemini_request(client: &GeminiClient, url: &str) -> Result<GeminiStream, GeminiError> {
+    // Extract out the domain/address, port and uri
+    let (addr, port) = _extract_host_port_from_url(url);
+    let addr = addr.trim_start_matches('[').trim_end_matches(']').to_string();
+    let client_identity = if client.identities {
+        // reads the identities directory, so it runs off the async workers like the tofu check
+        let url = url.to_string();
+        tokio::task::spawn_blocking(move || identity::identity_for_url(&url)).await.ok().flatten()
+    } else {
+        None
+    };
+    if client_identity.is_some() {
+        _gemini_handshake(client, &addr, port, None).await?;
+    }
+    let (mut stream, peer_cert) = _gemini_handshake(client, &addr, port, client_identity).await?;
+
+    // User input is already encoded, see GeminiClient::get_input
+    send_request(&mut stream, url).await?;
+    _gemini_read_header(client, Box::new(stream), url.to_string(), peer_cert).await
+}
+
+/// Connects to addr:port over TLS, presenting client_identity if given, and checks the server's certificate with the tofu module.
+/// Returns the stream and the server's certificate.
+async fn _gemini_handshake(client: &GeminiClient, addr: &str, port: u16, client_identity: Option<native_tls::Identity>) -> Result<(TlsStream<TcpStream>, Vec<u8>), GeminiError> {
+    let stream = timeout(client.connect_timeout, _gemini_connect(addr, port)).await
+        .map_err(|_| GeminiError::Timeout { stage: TimeoutStage::Connect, after: client.connect_timeout })??;
+
+    // All gemini communication uses TLS
+    let stream = timeout(client.handshake_timeout, tls_connect(addr, stream, client_identity)).await
+        .map_err(|_| GeminiError::Timeout { stage: TimeoutStage::Handshake, after: client.handshake_timeout })?
+        .map_err(|e| GeminiError::Tls(format!("TLS handshake failed!\n{}", e)))?;
+    let Ok(Some(cert)) = stream.get_ref().peer_certificate() else {
+        return Err(GeminiError::Tls("Server did not present a certificate".to_string()))
+    };
+    let peer_cert = cert.to_der().map_err(|e| GeminiError::Tls(format!("Certificate could not be encoded: {}", e)))?;
+    let host = addr.to_string();
+    tokio::task::spawn_blocking(move || tofu::tofu_handle_certificate(cert, &host, port)).await
+        .map_err(|e| GeminiError::Tofu(TofuError::Invalid(format!("Certificate check failed: {}", e))))?
+        .map_err(GeminiError::Tofu)?;
+    Ok((stream, peer_cert))
+}
 
-        // Otherwise, return the result
-        return (code, header, body);
+/// Resolves host and opens a TCP connection to it on port
+async fn _gemini_connect(host: &str, port: u16) -> Result<TcpStream, GeminiError> {
+    let socket_addrs: Vec<_> = tokio::net::lookup_host((host, port)).await
+        .map_err(|e| GeminiError::Dns(format!("Could not resolve {}: {}", host, e)))?
+        .collect();
+    if socket_addrs.is_empty() {
+        return Err(GeminiError::Dns(format!("{} has no addresses", host)));
     }
+    TcpStream::connect(&socket_addrs[..]).await
+        .map_err(|e| GeminiError::Tcp(format!("Could not connect to {}:{}: {}", host, port, e)))
+}
 
-    (
-        StatusCode::FailureClient,
-        "".to_string(),
-        "Too many redirects".to_string(),
-    )
+/// Wraps a connected TcpStream in TLS, presenting client_identity to the server if given.
+/// Certificate validity is not checked here; trust is decided by the tofu module.
+async fn tls_connect(domain: &str, stream: TcpStream, client_identity: Option<native_tls::Identity>) -> Result<TlsStream<TcpStream>, native_tls::Error> {
+    let mut builder = native_tls::TlsConnector::builder();
+    builder.danger_accept_invalid_certs(true);
+    if let Some(client_identity) = client_identity {
+        builder.identity(client_identity);
+    }
+    TlsConnector::from(builder.build()?).connect(domain, stream).await
+}
+
+/// Writes a single request line for url to the stream
+async fn send_request(stream: &mut TlsStream<TcpStream>, url: &str) -> Result<(), GeminiError> {
+    let request = client_build_request_str(url.to_string());
+    stream.write_all(request.as_bytes()).await
+        .map_err(|e| GeminiError::Tcp(format!("Error while writing to TLS stream!\n{}", e)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    /// Parses a response that did not come over the network
+    fn parse(response: Vec<u8>) -> Result<GeminiResponse, GeminiError> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            let client = GeminiClient::builder().build();
+            let reader = Box::new(std::io::Cursor::new(response));
+            _gemini_read_header(&client, reader, "gemini://example.org/".to_string(), Vec::new()).await?
+                .into_response().await
+        })
+    }
+
+    /// Converts a parsed response into (status, meta, body as String) for easier comparisons
+    fn as_text(response: Result<GeminiResponse, GeminiError>) -> (StatusCode, String, String) {
+        let response = response.unwrap();
+        (response.status, response.meta, String::from_utf8(response.body).unwrap())
+    }
+
     #[test]
     fn test_address_extraction() {
         let in0 = "https://foobar.com";
@@ -309,15 +873,75 @@ mod tests {
         let out1 = "my-website.com"; 
         let in2 = "google.com";
         let out2 = "google.com";
-        assert_eq!(_extract_address_from_url(&in0.to_string()), out0);
-        assert_eq!(_extract_address_from_url(&in1.to_string()), out1);
-        assert_eq!(_extract_address_from_url(&in2.to_string()), out2);
+        assert_eq!(_extract_address_from_url(in0), out0);
+        assert_eq!(_extract_address_from_url(in1), out1);
+        assert_eq!(_extract_address_from_url(in2), out2);
     }
 
     #[test]
-    fn test_get_gemini_invalid_domain() {
-        let (code, _, _) = get_gemini("this_website_does_not_exist_999.au".to_string());
-        assert_eq!(code, StatusCode::FailureClient)
+    fn test_host_port_extraction() {
+        assert_eq!(_extract_host_port_from_url("gemini://example.org:1966/path"), ("example.org".to_string(), 1966));
+        assert_eq!(_extract_host_port_from_url("example.org:1966"), ("example.org".to_string(), 1966));
+        assert_eq!(_extract_host_port_from_url("example.org/path:1966"), ("example.org".to_string(), DEFAULT_PORT));
+        assert_eq!(_extract_host_port_from_url("example.org?q=a:b"), ("example.org".to_string(), DEFAULT_PORT));
+        assert_eq!(_extract_host_port_from_url("gemini://example.org"), ("example.org".to_string(), DEFAULT_PORT));
+        assert_eq!(_extract_host_port_from_url("example.org:notaport/"), ("example.org".to_string(), DEFAULT_PORT));
+        assert_eq!(_extract_host_port_from_url("[::1]:1966/foo"), ("[::1]".to_string(), 1966));
+        assert_eq!(_extract_host_port_from_url("gemini://[::1]/foo"), ("[::1]".to_string(), DEFAULT_PORT));
+    }
+
+    #[tokio::test]
+    async fn test_get_gemini_invalid_domain() {
+        let response = get_gemini("this_website_does_not_exist_999.au".to_string()).await;
+        assert!(matches!(response, Err(GeminiError::Dns(_))), "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn test_stream_body() {
+        let (mut capsule, reader) = tokio::io::duplex(1024);
+        let client = GeminiClient::builder().body_timeout(Duration::from_millis(200)).build();
+        capsule.write_all(b"20 text/gemini\r\n# first").await.unwrap();
+        let mut response = _gemini_read_header(&client, Box::new(reader), "gemini://example.org/".to_string(), Vec::new()).await.unwrap();
+        assert_eq!((response.status, response.meta.as_str()), (StatusCode::Success, "text/gemini"));
+
+        // parts of the body are handed out while the connection is still open
+        assert_eq!(response.body.chunk().await, Ok(Some(b"# first".to_vec())));
+        capsule.write_all(b"\nsecond").await.unwrap();
+        assert_eq!(response.body.chunk().await, Ok(Some(b"\nsecond".to_vec())));
+        assert_eq!(response.body.received, 30);
+
+        // a pause longer than body_timeout fails, however long the body took so far
+        let after = Duration::from_millis(200);
+        assert_eq!(response.body.chunk().await, Err(GeminiError::Timeout { stage: TimeoutStage::Body, after }));
+        drop(capsule);
+        assert_eq!(response.body.chunk().await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_stream_body_too_large() {
+        let (mut capsule, reader) = tokio::io::duplex(1024);
+        let client = GeminiClient::builder().max_response_size(32).build();
+        capsule.write_all(b"20 text/gemini\r\n# first\n").await.unwrap();
+        let mut response = _gemini_read_header(&client, Box::new(reader), "gemini://example.org/".to_string(), Vec::new()).await.unwrap();
+        assert_eq!(response.body.chunk().await, Ok(Some(b"# first\n".to_vec())));
+        // streamed bodies end once the response outgrows max_response_size
+        capsule.write_all(&[b'a'; 16]).await.unwrap();
+        assert_eq!(response.body.chunk().await, Err(GeminiError::TooLarge { limit: 32 }));
+        assert_eq!(response.body.chunk().await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_get_handshake_timeout() {
+        // accepts the connection but never starts the TLS handshake
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move { listener.accept().await.map(|(stream, _)| stream) });
+        let client = GeminiClient::builder().handshake_timeout(Duration::from_millis(200)).build();
+        let response = client.get(&format!("127.0.0.1:{port}/")).await;
+        let after = Duration::from_millis(200);
+        assert_eq!(response, Err(GeminiError::Timeout { stage: TimeoutStage::Handshake, after }));
+        assert_eq!(response.unwrap_err().as_str(), "Handshake Timed Out");
+        drop(server);
     }
 
     #[test]
@@ -326,17 +950,21 @@ mod tests {
         let in0 = "10 What is the answer? To life, the universe\n and everything? \r\n".to_string();
         let out0 = (StatusCode::from(10), 
             "What is the answer? To life, the universe\n and everything? ".to_string(), "".to_string());
-        assert_eq!(out0, extract_response_header(in0));
+        assert_eq!(out0, as_text(parse(in0.into_bytes())));
 
         let in1 = "11 P4$$w0rd\npl0x~\r\n".to_string();
         let out1 = (StatusCode::from(11),
             "P4$$w0rd\npl0x~".to_string(), "".to_string());
-        assert_eq!(out1, extract_response_header(in1));
+        assert_eq!(out1, as_text(parse(in1.into_bytes())));
         
         let in2 = "12 foo\r\n".to_string();
         let out2 = (StatusCode::from(12),
             "foo".to_string(), "".to_string());
-        assert_eq!(out2, extract_response_header(in2));
+        assert_eq!(out2, as_text(parse(in2.clone().into_bytes())));
+        // the code sent is kept even though 12 is filed as InputExpected
+        let response = parse(in2.into_bytes()).unwrap();
+        assert_eq!((response.status, response.raw_code), (StatusCode::InputExpected, 12));
+        assert_eq!(response.url, "gemini://example.org/");
     }
 
     #[test]
@@ -345,12 +973,62 @@ mod tests {
         let in0 = "20 text/html\r\n Hello-!\nWorld\r\n1234#!\";;".to_string();
         let out0 = (StatusCode::from(20),
             "text/html".to_string(), " Hello-!\nWorld\r\n1234#!\";;".to_string());
-        assert_eq!(out0, extract_response_header(in0));
+        assert_eq!(out0, as_text(parse(in0.into_bytes())));
 
         let in1 = "29 some/meme\r\noiiaioiiiai\r".to_string();
         let out1 = (StatusCode::from(29),
             "some/meme".to_string(),"oiiaioiiiai\r".to_string());
-        assert_eq!(out1, extract_response_header(in1));
+        assert_eq!(out1, as_text(parse(in1.into_bytes())));
+    }
+
+    #[test]
+    /// Test that binary bodies are kept byte for byte
+    fn test_extract_response_header_binary_body() {
+        let mut in0 = b"20 image/png\r\n".to_vec();
+        let png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0xff, 0xfe];
+        in0.extend_from_slice(&png);
+        let response = parse(in0).unwrap();
+        assert_eq!((response.status, response.meta, response.body), (StatusCode::from(20), "image/png".to_string(), png));
+
+        // latin-1 encoded text must not be rejected
+        let in1 = b"20 text/plain; charset=iso-8859-1\r\ncaf\xe9".to_vec();
+        let response = parse(in1).unwrap();
+        assert_eq!((response.status, response.meta, response.body),
+            (StatusCode::from(20), "text/plain; charset=iso-8859-1".to_string(), b"caf\xe9".to_vec()));
+    }
+
+    #[test]
+    /// Test parsing of the META of 2* responses into a media type
+    fn test_media_type_parse() {
+        let m0 = MediaType::parse("text/gemini; charset=utf-8; lang=en");
+        assert_eq!(m0.mime, "text/gemini");
+        assert_eq!(m0.param("charset"), Some("utf-8"));
+        assert_eq!(m0.param("LANG"), Some("en"));
+        assert!(m0.is_gemtext());
+        assert_eq!(m0.to_string(), "text/gemini; charset=utf-8; lang=en");
+
+        let m1 = MediaType::parse("Image/PNG");
+        assert_eq!(m1.mime, "image/png");
+        assert!(m1.params.is_empty());
+        assert!(!m1.is_gemtext());
+
+        // empty META defaults to text/gemini
+        let m2 = MediaType::parse("");
+        assert!(m2.is_gemtext());
+        assert_eq!(m2.param("charset"), None);
+
+        // malformed mime defaults to text/gemini, quoted and malformed params are handled
+        let m3 = MediaType::parse("not a mime; charset=\"UTF-8\"; novalue; =x");
+        assert!(m3.is_gemtext());
+        assert_eq!(m3.params, vec![("charset".to_string(), "UTF-8".to_string())]);
+    }
+
+    #[test]
+    /// Test that text bodies are decoded with the declared charset
+    fn test_media_type_decode_text() {
+        assert_eq!(MediaType::parse("text/gemini").decode_text("héllo".as_bytes()), "héllo");
+        assert_eq!(MediaType::parse("text/gemini; charset=iso-8859-1").decode_text(b"caf\xe9"), "café");
+        assert_eq!(MediaType::parse("text/gemini; charset=bogus").decode_text(b"ok\xff"), "ok\u{fffd}");
     }
 
     #[test]
@@ -361,35 +1039,35 @@ mod tests {
         let out0 = (StatusCode::from(30),
                     "gemini://example.com/new/path".to_string(),
                     "".to_string()); // Body should be empty for redirects
-        assert_eq!(out0, extract_response_header(in0));
+        assert_eq!(out0, as_text(parse(in0.into_bytes())));
 
         // Test case for 31 (Permanent redirect)
         let in1 = "31 /local/resource\r\n".to_string();
         let out1 = (StatusCode::from(31),
                     "/local/resource".to_string(),
                     "".to_string());
-        assert_eq!(out1, extract_response_header(in1));
+        assert_eq!(out1, as_text(parse(in1.into_bytes())));
 
         // Test case for 32 (Temporary redirect with a more complex URI-reference)
         let in2 = "32 gemini://mirror.gmi/path?query=1&frag#section\r\n".to_string();
         let out2 = (StatusCode::from(32),
                     "gemini://mirror.gmi/path?query=1&frag#section".to_string(),
                     "".to_string());
-        assert_eq!(out2, extract_response_header(in2));
+        assert_eq!(out2, as_text(parse(in2.into_bytes())));
 
         // Test case for 39 (General 3x response with a simple path)
         let in3 = "39 /another/place\r\n".to_string();
         let out3 = (StatusCode::from(39),
                     "/another/place".to_string(),
                     "".to_string());
-        assert_eq!(out3, extract_response_header(in3));
+        assert_eq!(out3, as_text(parse(in3.into_bytes())));
 
         // Edge case: URI-reference with special characters that are still valid
         let in4 = "30 /some/path with spaces/and!symbols.gmi\r\n".to_string();
         let out4 = (StatusCode::from(30),
                     "/some/path with spaces/and!symbols.gmi".to_string(),
                     "".to_string());
-        assert_eq!(out4, extract_response_header(in4));
+        assert_eq!(out4, as_text(parse(in4.into_bytes())));
     }
 
     #[test]
@@ -400,42 +1078,42 @@ mod tests {
         let out0 = (StatusCode::from(40),
                     "Service Unavailable".to_string(),
                     "".to_string());
-        assert_eq!(out0, extract_response_header(in0));
+        assert_eq!(out0, as_text(parse(in0.into_bytes())));
 
         // Test case for 41 without an error message
         let in1 = "41\r\n".to_string();
         let out1 = (StatusCode::from(41),
                     "".to_string(), // Error message is empty
                     "".to_string());
-        assert_eq!(out1, extract_response_header(in1));
+        assert_eq!(out1, as_text(parse(in1.into_bytes())));
 
         // Test case for 42 
         let in2 = "42 CGI error~\r\n".to_string();
         let out2 = (StatusCode::from(42),
                     "CGI error~".to_string(),
                     "".to_string());
-        assert_eq!(out2, extract_response_header(in2));
+        assert_eq!(out2, as_text(parse(in2.into_bytes())));
 
         // Test case for 43
         let in3 = "43 Prox failed(cf http 502,504)\r\n".to_string();
         let out3 = (StatusCode::from(43),
                     "Prox failed(cf http 502,504)".to_string(),
                     "".to_string());
-        assert_eq!(out3, extract_response_header(in3));
+        assert_eq!(out3, as_text(parse(in3.into_bytes())));
 
         // Test case for 44
         let in4 = "44 SLOW DOWN!\r\n".to_string();
         let out4 = (StatusCode::from(44),
                     "SLOW DOWN!".to_string(),
                     "".to_string());
-        assert_eq!(out4, extract_response_header(in4));
+        assert_eq!(out4, as_text(parse(in4.into_bytes())));
 
         // Test case for 49 with a more specific error message
         let in5 = "49 Rate Limit Exceeded: Try again in 60s\r\n".to_string();
         let out5 = (StatusCode::from(49),
                     "Rate Limit Exceeded: Try again in 60s".to_string(),
                     "".to_string());
-        assert_eq!(out5, extract_response_header(in5));
+        assert_eq!(out5, as_text(parse(in5.into_bytes())));
     }
 
     #[test]
@@ -446,21 +1124,21 @@ mod tests {
         let out0 = (StatusCode::from(50),
                     "Not Found".to_string(),
                     "".to_string());
-        assert_eq!(out0, extract_response_header(in0));
+        assert_eq!(out0, as_text(parse(in0.into_bytes())));
 
         // Test case for 51 with an error message
         let in1 = "51 Bad Request\r\n".to_string();
         let out1 = (StatusCode::from(51),
                     "Bad Request".to_string(),
                     "".to_string());
-        assert_eq!(out1, extract_response_header(in1));
+        assert_eq!(out1, as_text(parse(in1.into_bytes())));
 
         // Test case for 59 without an error message
         let in2 = "59\r\n".to_string();
         let out2 = (StatusCode::from(59),
                     "".to_string(),
                     "".to_string());
-        assert_eq!(out2, extract_response_header(in2));
+        assert_eq!(out2, as_text(parse(in2.into_bytes())));
     }
 
     #[test]
@@ -471,82 +1149,89 @@ mod tests {
         let out0 = (StatusCode::from(60),
                     "Authentication Required".to_string(),
                     "".to_string());
-        assert_eq!(out0, extract_response_header(in0));
+        assert_eq!(out0, as_text(parse(in0.into_bytes())));
 
         // Test case for 61 with an error message
         let in1 = "61 Invalid Client Certificate\r\n".to_string();
         let out1 = (StatusCode::from(61),
                     "Invalid Client Certificate".to_string(),
                     "".to_string());
-        assert_eq!(out1, extract_response_header(in1));
+        assert_eq!(out1, as_text(parse(in1.into_bytes())));
 
         // Test case for 69 without an error message
         let in2 = "69\r\n".to_string();
         let out2 = (StatusCode::from(69),
                     "".to_string(),
                     "".to_string());
-        assert_eq!(out2, extract_response_header(in2));
+        assert_eq!(out2, as_text(parse(in2.into_bytes())));
     }
 
     #[test]
     /// Test that status codes from non [1*, 6*] are handled correctly 
     fn test_extract_response_header_unknown_statuscode() {
-        let in0 = "71\r\n".to_string();
-        let out0 = (StatusCode::from(71),
-                    "Server returned invalid status code!".to_string(),
-                    "".to_string());
-        assert_eq!(out0, extract_response_header(in0));
-
-        let in1 = "84 Hello there!\r\n".to_string();
-        let out1 = (StatusCode::from(84),
-                    "Server returned invalid status code!".to_string(),
-                    "".to_string());
-        assert_eq!(out1, extract_response_header(in1));
+        let invalid = Err(GeminiError::Protocol("Server returned invalid status code!".to_string()));
+        assert_eq!(parse(b"71\r\n".to_vec()), invalid);
+        assert_eq!(parse(b"84 Hello there!\r\n".to_vec()), invalid);
+        assert_eq!(parse(b"99 Brooklyn\r\nCool, cool, cool, cool, cool. No doubt, no doubt, no doubt.".to_vec()), invalid);
+        assert_eq!(parse(b"123 Richard\r\nStallman".to_vec()), invalid);
+        assert_eq!(parse(b"5 Richard\r\nStallman".to_vec()), invalid);
+        assert_eq!(parse(b"-6 Edgar\r\nDijkstra".to_vec()), invalid);
+        assert_eq!(parse(b"+6 Edgar\r\nDijkstra".to_vec()), invalid);
+        assert_eq!(parse(b"20 text/gemini".to_vec()), Err(GeminiError::Protocol("Response does not have CRLF!".to_string())));
+    }
 
-        let in2 = "99 Brooklyn\r\nCool, cool, cool, cool, cool. No doubt, no doubt, no doubt.".to_string();
-        let out2 = (StatusCode::from(84),
-                    "Server returned invalid status code!".to_string(),
-                    "".to_string());
-        assert_eq!(out2, extract_response_header(in2));
+    #[test]
+    fn test_encode_query() {
+        assert_eq!(gemini_encode_query("hello world", QueryEncoding::Percent), "hello%20world");
+        assert_eq!(gemini_encode_query("a+b=c&d?e#f%", QueryEncoding::Percent), "a%2Bb%3Dc%26d%3Fe%23f%25");
+        assert_eq!(gemini_encode_query("ドキュメント", QueryEncoding::Percent), "%E3%83%89%E3%82%AD%E3%83%A5%E3%83%A1%E3%83%B3%E3%83%88");
+        assert_eq!(gemini_encode_query("safe-._~", QueryEncoding::Percent), "safe-._~");
+        assert_eq!(gemini_encode_query("line\r\nbreak", QueryEncoding::Percent), "line%0D%0Abreak");
+        assert_eq!(gemini_encode_query("", QueryEncoding::Percent), "");
+    }
 
-        let in3 = "123 Richard\r\nStallman".to_string();
-        let out3 = (StatusCode::from(84),
-                    "Server returned invalid status code!".to_string(),
-                    "".to_string());
-        assert_eq!(out3, extract_response_header(in3));
+    #[test]
+    fn test_encode_query_raw() {
+        assert_eq!(gemini_encode_query("hello world", QueryEncoding::Raw), "hello world");
+        assert_eq!(gemini_encode_query("a+b=c&d", QueryEncoding::Raw), "a+b=c&d");
+        assert_eq!(gemini_encode_query("ドキュメント", QueryEncoding::Raw), "ドキュメント");
+        // these would end the request line or be read as an escape
+        assert_eq!(gemini_encode_query("100%\r\nGET", QueryEncoding::Raw), "100%25%0D%0AGET");
+    }
 
-        let in4 = "5 Richard\r\nStallman".to_string();
-        let out4 = (StatusCode::StatusUnknown,
-                    "Server returned invalid status code!".to_string(),
-                    "".to_string());
-        assert_eq!(out4, extract_response_header(in4));
+    #[test]
+    fn test_query_encoding() {
+        let client = GeminiClient::builder().raw_query_hosts(vec!["Raw.example".to_string()]).build();
+        assert_eq!(client.query_encoding("gemini://raw.example:1966/search"), QueryEncoding::Raw);
+        assert_eq!(client.query_encoding("example.org/search"), QueryEncoding::Percent);
+    }
 
-        let in5 = "-6 Edgar\r\nDijkstra".to_string();
-        let out5 = (StatusCode::StatusUnknown,
-                    "Server returned invalid status code!".to_string(),
-                    "".to_string());
-        assert_eq!(out5, extract_response_header(in5));
+    #[test]
+    fn test_input_queries() {
+        // encoded first, then as typed for servers that answer 59 to encoded input
+        assert_eq!(_gemini_input_queries("hello world", QueryEncoding::Percent), vec!["hello%20world", "hello world"]);
+        assert_eq!(_gemini_input_queries("plain", QueryEncoding::Percent), vec!["plain"]);
+        assert_eq!(_gemini_input_queries("hello world", QueryEncoding::Raw), vec!["hello world"]);
+        assert_eq!(_gemini_input_queries("", QueryEncoding::Percent), vec![""]);
     }
 
     #[test]
-    /// Test that the slug encoder works as expected
-    /// Test for basic functionality and utf8 encoding
-    fn test_encode_url_suffix() {
-        assert_eq!(encode_url_suffix("foo/bar/baz".to_string()), "foo/bar/baz");
-        assert_eq!(encode_url_suffix("a/b/c".to_string()), "a/b/c");
-        assert_eq!(encode_url_suffix("data/document/ドキュメント.pdf".to_string()), "data/document/%E3%83%89%E3%82%AD%E3%83%A5%E3%83%A1%E3%83%B3%E3%83%88.pdf");
-        assert_eq!(encode_url_suffix("justthefile.txt".to_string()), "justthefile.txt"); // No slashes, entire string is 'baz'
-        assert_eq!(encode_url_suffix("category/subcategory/item/specific file.gem".to_string()), "category/subcategory/item/specific+file.gem");
-        assert_eq!(encode_url_suffix("test/query/foo?bar=baz".to_string()), "test/query/foo?bar%3Dbaz");
-}    
+    fn test_with_query() {
+        assert_eq!(_gemini_with_query("example.org/search", "a%20b").unwrap(), "gemini://example.org/search?a%20b");
+        // the input replaces the query of the prompt page, and fragments are never sent
+        assert_eq!(_gemini_with_query("gemini://example.org/search?old#top", "new").unwrap(), "gemini://example.org/search?new");
+        assert_eq!(_gemini_with_query("example.org:1966/s#frag", "a b").unwrap(), "gemini://example.org:1966/s?a b");
+        assert!(_gemini_with_query("gemini://exa mple.org/", "x").is_err());
+    }
 
     #[test]
-    fn test_encode_url_suffix_edge_cases() {
-        // Empty input string
-        assert_eq!(encode_url_suffix("".to_string()), "");
-        // Path with spaces
-        assert_eq!(encode_url_suffix("/root/path/file with spaces".to_string()), "/root/path/file+with+spaces");
-        // Path with spaced subdirectory
-        assert_eq!(encode_url_suffix("dir/subdir/ ".to_string()), "dir/subdir/+");
-    }
-}
\ No newline at end of file
+    fn test_resolve_redirect() {
+        assert_eq!(_resolve_redirect("gemini://a.net/b/c", "d").unwrap(), "gemini://a.net/b/d");
+        assert_eq!(_resolve_redirect("gemini://a.net/b/c", "/e?x").unwrap(), "gemini://a.net/e?x");
+        // another host or port gets its own connection
+        assert_eq!(_resolve_redirect("gemini://a.net/b", "gemini://b.net:1966/").unwrap(), "gemini://b.net:1966/");
+        assert_eq!(_resolve_redirect("gemini://a.net/b", "//c.net/x").unwrap(), "gemini://c.net/x");
+        assert!(_resolve_redirect("gemini://a.net/b", "https://a.net/").is_err());
+        assert!(_resolve_redirect("gemini://a.net/b", "gemini://").is_err());
+    }
+}
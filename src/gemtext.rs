@@ -1,77 +1,291 @@
-use comrak::ComrakOptions;
 use url::Url;
 
-/// Given a gemtext string, perform some manipulations and return the desired result
+use crate::gemini::gemini_default_port;
 
-/// Takes in a gemtext string, converts it to md then converts it to html
-/// baseurl is used to relativize all links the baseurl provided. leave as empty string if not needed
+// Parses gemtext into lines and renders them as HTML.
+// See: https://portal.mozz.us/gemini/geminiprotocol.net/docs/gemtext-specification.gmi
+
+/// A single line of a gemtext document
+#[derive(Debug, PartialEq, Clone)]
+pub enum GemtextLine {
+    Text(String),
+    Link { url: String, label: Option<String> },
+    /// Level is 1 to 3
+    Heading { level: u8, text: String },
+    ListItem(String),
+    Quote(String),
+    /// A ``` line. The alt text is only meaningful on the toggle that opens a block.
+    PreformatToggle { alt: String },
+    /// A line between two toggles, kept verbatim
+    Preformatted(String),
+}
+
+/// Takes in a gemtext string, parses it and renders it to html
+/// url is the page's own url, used to resolve relative links into proxy links
 pub fn gemtext_to_html(gemtext: String, url: String) -> String {
-    let md = gemtext_to_md(gemtext, url);
-    let options = ComrakOptions {
-        render: comrak::ComrakRenderOptions {
-            hardbreaks: true,
-            unsafe_: true, // allow raw HTML
-            ..Default::default()
-         },
-        ..Default::default()
-    };
-    comrak::markdown_to_html(&md, &options)
+    render_html(&parse_gemtext(&gemtext), &url)
+}
+
+/// Parses gemtext line by line.
+/// Line types are decided by their first characters, except inside preformatted blocks
+/// where every line but a closing toggle is preformatted text.
+pub fn parse_gemtext(gemtext: &str) -> Vec<GemtextLine> {
+    let mut preformatted = false;
+    gemtext.lines()
+        .map(|line| parse_next_line(line, &mut preformatted))
+        .collect()
+}
+
+/// Parses the next line of a document, preformatted tells whether it is inside a preformatted block
+/// and is flipped by toggles
+fn parse_next_line(line: &str, preformatted: &mut bool) -> GemtextLine {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    if let Some(alt) = line.strip_prefix("```") {
+        *preformatted = !*preformatted;
+        return GemtextLine::PreformatToggle { alt: alt.trim().to_string() };
+    }
+    if *preformatted {
+        return GemtextLine::Preformatted(line.to_string());
+    }
+    parse_line(line)
+}
+
+/// Parses a single line outside of a preformatted block
+fn parse_line(line: &str) -> GemtextLine {
+    if let Some(rest) = line.strip_prefix("=>") {
+        let rest = rest.trim();
+        let (url, label) = match rest.split_once(char::is_whitespace) {
+            Some((url, label)) => (url, label.trim()),
+            None => (rest, ""),
+        };
+        if url.is_empty() {
+            return GemtextLine::Text(line.to_string());
+        }
+        let label = (!label.is_empty()).then(|| label.to_string());
+        return GemtextLine::Link { url: url.to_string(), label };
+    }
+    if line.starts_with('#') {
+        let level = line.chars().take(3).take_while(|c| *c == '#').count();
+        return GemtextLine::Heading { level: level as u8, text: line[level..].trim().to_string() };
+    }
+    if let Some(item) = line.strip_prefix("* ") {
+        return GemtextLine::ListItem(item.trim().to_string());
+    }
+    if let Some(quote) = line.strip_prefix('>') {
+        return GemtextLine::Quote(quote.trim().to_string());
+    }
+    GemtextLine::Text(line.to_string())
+}
+
+/// Renders parsed gemtext to html.
+/// All text is escaped so it shows literally. Consecutive list items and quotes are grouped,
+/// and preformatted blocks become a `<pre>` labelled with their alt text.
+pub fn render_html(lines: &[GemtextLine], url: &str) -> String {
+    let mut renderer = GemtextRenderer::new(url);
+    let mut html = String::new();
+    for line in lines {
+        html.push_str(&renderer.render_line(line));
+    }
+    html.push_str(&renderer.finish());
+    html
+}
+
+/// Longest line GemtextRenderer waits to complete, in bytes
+pub const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+/// A streamed line grew past MAX_LINE_LENGTH without ending, see GemtextRenderer::push_text
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineTooLong;
+
+impl std::fmt::Display for LineTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "A line is longer than {} bytes", MAX_LINE_LENGTH)
+    }
+}
+
+/// Renders gemtext to html while it arrives, for pages that are shown before they are complete.
+/// Pushing a document in any number of pieces gives the same html as render_html.
+#[derive(Debug, Clone)]
+pub struct GemtextRenderer {
+    url: String,
+    /// The end of the last pushed text, waiting for its line to be complete
+    partial: String,
+    preformatted: bool,
+    group: Option<&'static str>, // the <ul> or <blockquote> currently open, if any
+    in_pre: bool,
+    quote_lines: usize,
+    /// The text of the first heading rendered so far
+    title: Option<String>,
 }
 
-/// Converts gemtext to md.
-/// See: https://portal.mozz.us/gemini/geminiprotocol.net/docs/gemtext-specification.gmi
-/// Fortunately, gemtext is close enough to markdown to allow minimal changes.
-/// All lines will be appended with a trailing \n
-fn gemtext_to_md(gemtext: String, _baseurl: String) -> String {
-    let mut result = String::new();
-    for line in gemtext.lines() {
-        let trimmed = line.trim();
-        if trimmed.starts_with("=>") {
-            result.push_str(&format!("{}\n", resolve_links(trimmed.to_string(), _baseurl.clone())));
-        } else {
-            result.push_str(&format!("{}\n", trimmed));
+impl GemtextRenderer {
+    /// url is the page's own url, used to resolve relative links into proxy links
+    pub fn new(url: &str) -> Self {
+        GemtextRenderer { url: url.to_string(), partial: String::new(), preformatted: false, group: None, in_pre: false, quote_lines: 0, title: None }
+    }
+
+    /// Renders the complete lines in text. The rest is kept until the next push or finish.
+    /// Once the kept rest is longer than MAX_LINE_LENGTH, the next push fails, so a capsule
+    /// that never ends its line cannot fill memory. The lines before it were already rendered.
+    pub fn push_text(&mut self, text: &str) -> Result<String, LineTooLong> {
+        if self.partial.len() > MAX_LINE_LENGTH {
+            return Err(LineTooLong);
+        }
+        self.partial.push_str(text);
+        let Some(end) = self.partial.rfind('\n') else {
+            return Ok(String::new());
+        };
+        let rest = self.partial.split_off(end + 1);
+        let complete = std::mem::replace(&mut self.partial, rest);
+        let mut html = String::new();
+        for line in complete.lines() {
+            let line = parse_next_line(line, &mut self.preformatted);
+            html.push_str(&self.render_line(&line));
         }
+        Ok(html)
     }
-    result
+
+    /// The text of the first heading, once it was rendered
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Renders a single parsed line
+    pub fn render_line(&mut self, line: &GemtextLine) -> String {
+        let mut html = String::new();
+        let line_group = match line {
+            GemtextLine::ListItem(_) => Some("ul"),
+            GemtextLine::Quote(_) => Some("blockquote"),
+            _ => None,
+        };
+        if self.group != line_group {
+            if let Some(tag) = self.group {
+                html.push_str(&format!("</{}>\n", tag));
+            }
+            if let Some(tag) = line_group {
+                html.push_str(&format!("<{}>", tag));
+                self.quote_lines = 0;
+            }
+            self.group = line_group;
+        }
+        match line {
+            GemtextLine::Text(text) if text.trim().is_empty() => html.push_str("<br>\n"),
+            GemtextLine::Text(text) => html.push_str(&format!("<p>{}</p>\n", escape_html(text))),
+            GemtextLine::Link { url: href, label } => {
+                let (href, display) = resolve_links(href, label.as_deref(), &self.url);
+                if is_safe_href(&href) {
+                    html.push_str(&format!("<p><a href=\"{}\">{}</a></p>\n", escape_html(&href), escape_html(&display)));
+                } else {
+                    html.push_str(&format!("<p>{}</p>\n", escape_html(&display)));
+                }
+            },
+            GemtextLine::Heading { level, text } => {
+                if self.title.is_none() && !text.trim().is_empty() {
+                    self.title = Some(text.trim().to_string());
+                }
+                html.push_str(&format!("<h{0}>{1}</h{0}>\n", level, escape_html(text)));
+            },
+            GemtextLine::ListItem(item) => html.push_str(&format!("<li>{}</li>", escape_html(item))),
+            GemtextLine::Quote(quote) => {
+                if self.quote_lines > 0 {
+                    html.push_str("<br>");
+                }
+                html.push_str(&escape_html(quote));
+                self.quote_lines += 1;
+            },
+            GemtextLine::PreformatToggle { .. } if self.in_pre => {
+                html.push_str("</pre>\n");
+                self.in_pre = false;
+            },
+            GemtextLine::PreformatToggle { alt } => {
+                if alt.is_empty() {
+                    html.push_str("<pre>");
+                } else {
+                    html.push_str(&format!("<pre role=\"img\" aria-label=\"{0}\" title=\"{0}\">", escape_html(alt)));
+                }
+                self.in_pre = true;
+            },
+            GemtextLine::Preformatted(text) => {
+                html.push_str(&escape_html(text));
+                html.push('\n');
+            },
+        }
+        html
+    }
+
+    /// Renders the last line, if it did not end with a newline, and closes any open group or preformatted block
+    pub fn finish(mut self) -> String {
+        let mut html = String::new();
+        if !self.partial.is_empty() {
+            let line = parse_next_line(&std::mem::take(&mut self.partial), &mut self.preformatted);
+            html.push_str(&self.render_line(&line));
+        }
+        if let Some(tag) = self.group {
+            html.push_str(&format!("</{}>\n", tag));
+        }
+        if self.in_pre {
+            html.push_str("</pre>\n");
+        }
+        html
+    }
+}
+
+/// Replaces the characters that are significant in HTML with their entities
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
 }
 
+/// Schemes a capsule may link to besides gemini, which is always turned into a proxy path
+const SAFE_LINK_SCHEMES: &[&str] = &[
+    "http", "https", "gopher", "finger", "spartan", "titan", "mailto", "ftp", "sftp", "irc", "ircs", "news", "nntp", "xmpp", "magnet",
+];
 
-fn resolve_links(link: String, url: String) -> String {
+/// Returns true if a resolved href may be put in a link: a proxy path or a url with a whitelisted scheme.
+/// Everything else, e.g. javascript: and data:, is rendered as plain text.
+fn is_safe_href(href: &str) -> bool {
+    if href.starts_with('/') && !href.starts_with("//") {
+        return true;
+    }
+    match href.split_once(':') {
+        Some((scheme, _)) => SAFE_LINK_SCHEMES.contains(&scheme.trim().to_ascii_lowercase().as_str()),
+        None => false,
+    }
+}
+
+/// Resolves a link's url against the page url and returns (href, display text).
+/// Gemini links are turned into proxy paths, other links are kept as-is.
+/// Links without a label display their resolved href.
+fn resolve_links(raw_href: &str, label: Option<&str>, url: &str) -> (String, String) {
     // Ensure URL is a proper Gemini URL for resolution
     let base_url = Url::parse(&format!("gemini://{}", url))
         .unwrap_or_else(|_| Url::parse("gemini://tmp/").unwrap());
 
-    // Remove leading "=>"
-    let trimmed = link.trim_start().strip_prefix("=>").unwrap_or(&link).trim();
-
-    // Split into href and optional label
-    let mut parts = trimmed.splitn(2, char::is_whitespace);
-    let raw_href = parts.next().unwrap_or("");
-    let label = parts.next().unwrap_or("").trim();
-
     // resolve urls
     let resolved_url = base_url.join(raw_href);
     match resolved_url {
         Ok(url) if url.scheme() == "gemini" => {
             let host = url.host_str().unwrap_or("invalid");
             let path = url.path();
-            let mut proxy_path = format!("/{host}{}", path);
+            // keep non-default ports so the proxy connects to the right place
+            let mut proxy_path = match url.port() {
+                Some(port) if port != gemini_default_port() => format!("/{host}:{port}{}", path),
+                _ => format!("/{host}{}", path),
+            };
             if let Some(q) = url.query() {
                 proxy_path.push('?');
                 proxy_path.push_str(q);
             }
 
-            let display = if label.is_empty() {
-                proxy_path.clone()
-            } else {
-                label.to_string()
-            };
-
-            format!("[{}]({})", display, proxy_path)
+            let display = label.unwrap_or(&proxy_path).to_string();
+            (proxy_path, display)
         }
         _ => { // http(s) or other protocol link
-            let display = if label.is_empty() { raw_href } else { label };
-            format!("[{}]({})", display, raw_href)
+            let display = label.unwrap_or(raw_href);
+            (raw_href.to_string(), display.to_string())
         }
     }
 }
@@ -80,67 +294,72 @@ fn resolve_links(link: String, url: String) -> String {
 mod tests {
     use super::*;
 
+    /// Parses link as a link line, resolves it against base and compares it to expected, written as [display](href)
+    fn check_link(link: &str, base: &str, expected: &str) {
+        let [GemtextLine::Link { url, label }] = &parse_gemtext(link)[..] else {
+            panic!("{} is not a link", link);
+        };
+        let (href, display) = resolve_links(url, label.as_deref(), base);
+        assert_eq!(format!("[{}]({})", display, href), expected);
+    }
+
     #[test]
     fn test_links() {
-        fn check(link: &str, base: &str, expected: &str) {
-            assert_eq!(resolve_links(link.to_string(), base.to_string()), expected);
-        }
-
-        check(
+        check_link(
             "=> /index.gmi Welcome",
             "gemi.dev/cgi-bin/wp.cgi/a?b=c",
             "[Welcome](/gemi.dev/index.gmi)"
         );
 
-        check(
+        check_link(
             "=> foo.gmi Foo",
             "gemi.dev/cgi-bin/wp.cgi/a?b=c",
             "[Foo](/gemi.dev/cgi-bin/wp.cgi/foo.gmi)"
         );
 
-        check(
+        check_link(
             "=> ../bar.gmi Go up",
             "gemi.dev/cgi-bin/wp.cgi/a",
             "[Go up](/gemi.dev/cgi-bin/bar.gmi)"
         );
 
-        check(
+        check_link(
             "=> gemini://example.com/docs/ External",
             "gemi.dev/docs/",
             "[External](/example.com/docs/)"
         );
 
-        check(
+        check_link(
             "=> https://google.com Google",
             "gemi.dev/docs/",
             "[Google](https://google.com)"
         );
 
-        check(
+        check_link(
             "=> help",
             "gemi.dev/docs/",
             "[/gemi.dev/docs/help](/gemi.dev/docs/help)"
         );
 
-        check(
+        check_link(
             "=> /help",
             "gemi.dev/docs/",
             "[/gemi.dev/help](/gemi.dev/help)"
         );
 
-        check(
+        check_link(
             "=> /help/me/find/this.gmi foo bar",
             "gemi.dev/docs/",
             "[foo bar](/gemi.dev/help/me/find/this.gmi)"
         );
 
-        check(
+        check_link(
             "=> help",
             "gemi.dev/docs/tutorial.gmi",
             "[/gemi.dev/docs/help](/gemi.dev/docs/help)"
         );
 
-        check(
+        check_link(
             "=> /cgi-bin/wp.cgi/view?Siege+of+Breteuil Siege of Breteuil",
             "gemi.dev/cgi-bin/wp.cgi/featured",
             "[Siege of Breteuil](/gemi.dev/cgi-bin/wp.cgi/view?Siege+of+Breteuil)"
@@ -148,4 +367,173 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_links_with_ports() {
+        check_link(
+            "=> /path Path",
+            "example.org:1966/docs/",
+            "[Path](/example.org:1966/path)"
+        );
+
+        check_link(
+            "=> foo.gmi",
+            "example.org:1966/docs/",
+            "[/example.org:1966/docs/foo.gmi](/example.org:1966/docs/foo.gmi)"
+        );
+
+        check_link(
+            "=> gemini://other.org:2000/x?y Other",
+            "example.org:1966/",
+            "[Other](/other.org:2000/x?y)"
+        );
+
+        // an explicit default port is dropped
+        check_link(
+            "=> gemini://example.org:1965/x Default",
+            "gemi.dev/",
+            "[Default](/example.org/x)"
+        );
+
+        // absolute links to another host do not inherit the base port
+        check_link(
+            "=> gemini://example.com/ Example",
+            "example.org:1966/",
+            "[Example](/example.com/)"
+        );
+
+    }
+
+    #[test]
+    fn test_parse_line_types() {
+        let gemtext = "plain *emphasis* _under_ 1. not a list\n\
+            \n\
+            => gemini://example.org/ Example   site\n\
+            =>/relative\n\
+            =>\n\
+            # One\n\
+            ##Two\n\
+            ### Three\n\
+            #### Four\n\
+            * item\n\
+            *not an item\n\
+            > quoted\n\
+            ```ascii art\n\
+            # not a heading\n\
+            => not a link\n\
+            ``` ignored alt\n\
+            \tindented code\r\n";
+        let expected = vec![
+            GemtextLine::Text("plain *emphasis* _under_ 1. not a list".to_string()),
+            GemtextLine::Text("".to_string()),
+            GemtextLine::Link { url: "gemini://example.org/".to_string(), label: Some("Example   site".to_string()) },
+            GemtextLine::Link { url: "/relative".to_string(), label: None },
+            GemtextLine::Text("=>".to_string()),
+            GemtextLine::Heading { level: 1, text: "One".to_string() },
+            GemtextLine::Heading { level: 2, text: "Two".to_string() },
+            GemtextLine::Heading { level: 3, text: "Three".to_string() },
+            GemtextLine::Heading { level: 3, text: "# Four".to_string() },
+            GemtextLine::ListItem("item".to_string()),
+            GemtextLine::Text("*not an item".to_string()),
+            GemtextLine::Quote("quoted".to_string()),
+            GemtextLine::PreformatToggle { alt: "ascii art".to_string() },
+            GemtextLine::Preformatted("# not a heading".to_string()),
+            GemtextLine::Preformatted("=> not a link".to_string()),
+            GemtextLine::PreformatToggle { alt: "ignored alt".to_string() },
+            GemtextLine::Text("\tindented code".to_string()),
+        ];
+        assert_eq!(parse_gemtext(gemtext), expected);
+    }
+
+    #[test]
+    fn test_render_text() {
+        assert_eq!(gemtext_to_html("*emph* _u_ 1. one\n| a | b |".to_string(), "gemi.dev/".to_string()),
+            "<p>*emph* _u_ 1. one</p>\n<p>| a | b |</p>\n");
+        assert_eq!(gemtext_to_html("a\n\nb".to_string(), "gemi.dev/".to_string()),
+            "<p>a</p>\n<br>\n<p>b</p>\n");
+        assert_eq!(gemtext_to_html("1 < 2 & \"3\"".to_string(), "gemi.dev/".to_string()),
+            "<p>1 &lt; 2 &amp; &quot;3&quot;</p>\n");
+    }
+
+    #[test]
+    fn test_render_links() {
+        assert_eq!(gemtext_to_html("=> /a?b=1&c=2 A & B".to_string(), "gemi.dev/".to_string()),
+            "<p><a href=\"/gemi.dev/a?b=1&amp;c=2\">A &amp; B</a></p>\n");
+        assert_eq!(gemtext_to_html("=> https://example.com".to_string(), "gemi.dev/".to_string()),
+            "<p><a href=\"https://example.com\">https://example.com</a></p>\n");
+    }
+
+    #[test]
+    fn test_render_unsafe_links() {
+        assert_eq!(gemtext_to_html("=> javascript:alert(1) Click".to_string(), "gemi.dev/".to_string()),
+            "<p>Click</p>\n");
+        assert_eq!(gemtext_to_html("=> JavaScript:alert(1)".to_string(), "gemi.dev/".to_string()),
+            "<p>JavaScript:alert(1)</p>\n");
+        assert_eq!(gemtext_to_html("=> data:text/html,<script>x</script> Data".to_string(), "gemi.dev/".to_string()),
+            "<p>Data</p>\n");
+        assert_eq!(gemtext_to_html("=> mailto:a@b.c Mail".to_string(), "gemi.dev/".to_string()),
+            "<p><a href=\"mailto:a@b.c\">Mail</a></p>\n");
+        assert!(!is_safe_href("//evil.example/"));
+        assert!(!is_safe_href("no-scheme"));
+    }
+
+    #[test]
+    fn test_render_headings() {
+        assert_eq!(gemtext_to_html("# a\n## b\n### <c>".to_string(), "gemi.dev/".to_string()),
+            "<h1>a</h1>\n<h2>b</h2>\n<h3>&lt;c&gt;</h3>\n");
+    }
+
+    #[test]
+    fn test_render_list_items() {
+        assert_eq!(gemtext_to_html("* a\n* b\ntext\n* c".to_string(), "gemi.dev/".to_string()),
+            "<ul><li>a</li><li>b</li></ul>\n<p>text</p>\n<ul><li>c</li></ul>\n");
+    }
+
+    #[test]
+    fn test_render_quotes() {
+        assert_eq!(gemtext_to_html(">a\n> b\n* c".to_string(), "gemi.dev/".to_string()),
+            "<blockquote>a<br>b</blockquote>\n<ul><li>c</li></ul>\n");
+    }
+
+    #[test]
+    fn test_render_preformatted() {
+        assert_eq!(gemtext_to_html("```\n  *x*\n<b>\n```".to_string(), "gemi.dev/".to_string()),
+            "<pre>  *x*\n&lt;b&gt;\n</pre>\n");
+        assert_eq!(gemtext_to_html("```cat \"face\"\n=^.^=\n```".to_string(), "gemi.dev/".to_string()),
+            "<pre role=\"img\" aria-label=\"cat &quot;face&quot;\" title=\"cat &quot;face&quot;\">=^.^=\n</pre>\n");
+        // an unterminated block is closed at the end of the document
+        assert_eq!(gemtext_to_html("* a\n```\nx".to_string(), "gemi.dev/".to_string()),
+            "<ul><li>a</li></ul>\n<pre>x\n</pre>\n");
+    }
+
+    #[test]
+    fn test_renderer_matches_render_html() {
+        let gemtext = "# Title\r\n* a\n* b\n> q1\n> q2\n\n```alt\n=> not a link\n```\n=> /page Page\ntrailing";
+        let expected = gemtext_to_html(gemtext.to_string(), "gemi.dev/".to_string());
+        // however the document is split, the html is the same
+        for size in [1, 2, 3, 7, gemtext.len()] {
+            let mut renderer = GemtextRenderer::new("gemi.dev/");
+            let mut html = String::new();
+            let bytes = gemtext.as_bytes();
+            for piece in bytes.chunks(size) {
+                html.push_str(&renderer.push_text(std::str::from_utf8(piece).unwrap()).unwrap());
+            }
+            html.push_str(&renderer.finish());
+            assert_eq!(html, expected, "pieces of {size} bytes");
+        }
+
+        // complete lines are rendered right away
+        let mut renderer = GemtextRenderer::new("gemi.dev/");
+        assert_eq!(renderer.title(), None);
+        assert_eq!(renderer.push_text("# Live\nchat"), Ok("<h1>Live</h1>\n".to_string()));
+        assert_eq!(renderer.push_text(" line\n## Later\n"), Ok("<p>chat line</p>\n<h2>Later</h2>\n".to_string()));
+        assert_eq!(renderer.title(), Some("Live"));
+        assert_eq!(renderer.finish(), "");
+
+        // a line that never ends fails once it is too long, complete lines are still rendered
+        let mut renderer = GemtextRenderer::new("gemi.dev/");
+        let endless = "a".repeat(MAX_LINE_LENGTH);
+        assert_eq!(renderer.push_text(&format!("done\n{endless}")), Ok("<p>done</p>\n".to_string()));
+        assert_eq!(renderer.push_text("a"), Ok(String::new()));
+        assert_eq!(renderer.push_text("a\n"), Err(LineTooLong));
+    }
 }
@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+
+// The history module remembers the pages visited through the proxy, so they can be found again on /.dioscuri/history.
+// It lives in ~/.dioscuri/history, one line per url with the time of the last visit, the status and the page title.
+// Visits are appended to the file in the background a moment after they happen, so a visit and the title
+// that follows it are written together, off the async workers. Deletions rewrite the whole file.
+// Answers to sensitive input prompts (status 11) are never recorded, see browser::render_response.
+
+const HISTORY_FILENAME: &str = "history";
+const HISTORY_VERSION: u32 = 1;
+const HISTORY_HEADER: &str = "# dioscuri history v";
+/// How many urls are kept. The least recently visited url is dropped first.
+const HISTORY_CAPACITY: usize = 5000;
+/// How long changes wait before they are written, so that the changes of one page load are written at once
+const HISTORY_WRITE_DELAY: Duration = Duration::from_secs(2);
+
+/// The last visit of a url.
+/// Timestamps are seconds since the unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// The gemini url without gemini://, as used in proxy paths
+    pub url: String,
+    /// The first heading of the page, if it is gemtext and has one
+    pub title: Option<String>,
+    pub visited: i64,
+    /// The raw status code of the response
+    pub status: u8,
+}
+
+impl HistoryEntry {
+    /// The host:port part of the url, which entries are grouped by
+    pub fn host(&self) -> &str {
+        self.url.split(['/', '?']).next().unwrap_or_default()
+    }
+}
+
+/// The history file, a single versioned file with tab separated lines:
+/// `{visited}\t{status}\t{url}\t{title}`
+/// Changed urls are appended, so a url may have several lines. The last one wins.
+/// Unlike known_hosts, malformed lines are skipped, losing an entry only loses a bit of history.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    /// Oldest visit first, one entry per url
+    entries: Vec<HistoryEntry>,
+    /// Urls changed since the file was last written
+    changed: Vec<String>,
+    /// Set once entries were removed, which appending cannot express
+    rewrite: bool,
+    /// Lines of entries in the file. It is rewritten once appends made it much longer than the history.
+    file_lines: usize,
+}
+
+/// A write of the history file, taken from the history by History::take_write
+#[derive(Debug, PartialEq)]
+enum HistoryWrite {
+    /// The whole file, written atomically
+    Replace(String),
+    /// Lines to append
+    Append(String),
+}
+
+impl History {
+    /// Loads the history at path. A missing file is an empty history.
+    pub fn load(path: &Path) -> Result<History, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Could not read {:?}: {}", path, e)),
+        };
+        let mut entries: Vec<HistoryEntry> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        let mut file_lines = 0;
+        for (idx, line) in contents.lines().enumerate() {
+            if let Some(version) = line.strip_prefix(HISTORY_HEADER) {
+                if idx == 0 && version.trim() != HISTORY_VERSION.to_string() {
+                    return Err(format!("{:?} has unsupported version {}", path, version.trim()));
+                }
+                continue;
+            }
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some(entry) = Self::parse_line(line) else {
+                println!("Skipping malformed line {} of {:?}", idx + 1, path);
+                continue;
+            };
+            file_lines += 1;
+            match positions.get(&entry.url) {
+                Some(&position) => entries[position] = entry,
+                None => {
+                    positions.insert(entry.url.clone(), entries.len());
+                    entries.push(entry);
+                },
+            }
+        }
+        entries.sort_by_key(|entry| entry.visited);
+        let mut history = History { path: path.to_path_buf(), entries, changed: Vec::new(), rewrite: false, file_lines };
+        history.truncate();
+        Ok(history)
+    }
+
+    fn parse_line(line: &str) -> Option<HistoryEntry> {
+        let mut fields = line.splitn(4, '\t');
+        let visited = fields.next()?.parse().ok()?;
+        let status = fields.next()?.parse().ok()?;
+        let url = fields.next().filter(|url| !url.is_empty())?.to_string();
+        let title = fields.next().filter(|title| !title.is_empty()).map(|title| title.to_string());
+        Some(HistoryEntry { url, title, visited, status })
+    }
+
+    fn line(entry: &HistoryEntry) -> String {
+        format!("{}\t{}\t{}\t{}\n", entry.visited, entry.status, entry.url, entry.title.as_deref().unwrap_or(""))
+    }
+
+    fn mark_changed(&mut self, url: &str) {
+        if !self.changed.iter().any(|changed| changed == url) {
+            self.changed.push(url.to_string());
+        }
+    }
+
+    fn truncate(&mut self) {
+        if self.entries.len() > HISTORY_CAPACITY {
+            self.entries.drain(..self.entries.len() - HISTORY_CAPACITY);
+        }
+    }
+
+    /// Records a visit of url at visited. A url visited before keeps its title until set_title replaces it.
+    /// Urls with control characters are not recorded, they could not be written on a single line.
+    /// Returns false if nothing was recorded.
+    pub fn record(&mut self, url: &str, status: u8, visited: i64) -> bool {
+        if url.is_empty() || url.chars().any(char::is_control) {
+            return false;
+        }
+        let title = self.entries.iter().position(|entry| entry.url == url)
+            .and_then(|idx| self.entries.remove(idx).title);
+        self.entries.push(HistoryEntry { url: url.to_string(), title, visited, status });
+        self.truncate();
+        self.mark_changed(url);
+        true
+    }
+
+    /// Sets the title of url, if it is in the history. Control characters, such as tabs, become spaces.
+    /// Returns false if the title did not change.
+    pub fn set_title(&mut self, url: &str, title: &str) -> bool {
+        let title: String = title.trim().chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+        let title = Some(title).filter(|title| !title.is_empty());
+        match self.entries.iter_mut().find(|entry| entry.url == url) {
+            Some(entry) if entry.title != title => {
+                entry.title = title;
+                self.mark_changed(url);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns the entries whose url or title contains query, ignoring case, most recent first.
+    /// An empty query matches every entry.
+    pub fn search(&self, query: &str) -> Vec<HistoryEntry> {
+        let query = query.trim().to_lowercase();
+        self.entries.iter().rev()
+            .filter(|entry| {
+                query.is_empty()
+                    || entry.url.to_lowercase().contains(&query)
+                    || entry.title.as_ref().is_some_and(|title| title.to_lowercase().contains(&query))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Removes url. Returns false if it was not in the history.
+    pub fn remove(&mut self, url: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.url != url);
+        self.rewrite |= self.entries.len() != len;
+        self.entries.len() != len
+    }
+
+    /// Removes every url on host. Returns false if there were none.
+    pub fn remove_host(&mut self, host: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.host() != host);
+        self.rewrite |= self.entries.len() != len;
+        self.entries.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.rewrite = true;
+    }
+
+    /// Returns the write that brings the file up to date, and considers the history written.
+    /// Changed urls are appended, unless entries were removed or the file is new or has grown too long,
+    /// in which case the whole history replaces it.
+    fn take_write(&mut self) -> Option<HistoryWrite> {
+        if !self.rewrite && self.changed.is_empty() {
+            return None;
+        }
+        let changed = std::mem::take(&mut self.changed);
+        if !self.rewrite && self.file_lines > 0 && self.file_lines + changed.len() <= 2 * HISTORY_CAPACITY {
+            let lines: String = changed.iter()
+                .filter_map(|url| self.entries.iter().find(|entry| &entry.url == url))
+                .map(Self::line)
+                .collect();
+            self.file_lines += changed.len();
+            return Some(HistoryWrite::Append(lines));
+        }
+        let mut contents = format!("{}{}\n", HISTORY_HEADER, HISTORY_VERSION);
+        contents.push_str("# visited (unix seconds)\tstatus\turl\ttitle\n");
+        contents.extend(self.entries.iter().map(Self::line));
+        self.rewrite = false;
+        self.file_lines = self.entries.len();
+        Some(HistoryWrite::Replace(contents))
+    }
+}
+
+impl HistoryWrite {
+    /// Writes to the history file at path. Replacing is atomic, like tofu::KnownHosts::save.
+    /// Appends are not synced to disk, a line cut short by a crash is skipped when the file is loaded.
+    fn apply(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match self {
+            HistoryWrite::Append(lines) => {
+                fs::OpenOptions::new().append(true).open(path)?.write_all(lines.as_bytes())
+            },
+            HistoryWrite::Replace(contents) => {
+                let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+                let mut tmp = fs::File::create(&tmp_path)?;
+                tmp.write_all(contents.as_bytes())?;
+                tmp.sync_all()?;
+                drop(tmp);
+                fs::rename(&tmp_path, path).inspect_err(|_| {
+                    let _ = fs::remove_file(&tmp_path);
+                })
+            },
+        }
+    }
+}
+
+/// The process wide history, loaded from ~/.dioscuri/history on first use
+fn store() -> Result<&'static Mutex<History>, String> {
+    static STORE: OnceLock<Result<Mutex<History>, String>> = OnceLock::new();
+    STORE.get_or_init(|| {
+        History::load(&config::config_get().storage_dir().join(HISTORY_FILENAME))
+            .inspect_err(|e| println!("History is disabled: {}", e))
+            .map(Mutex::new)
+    })
+        .as_ref()
+        .map_err(|e| e.clone())
+}
+
+/// Set while a write of the history is scheduled
+static WRITE_SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+/// Applies change to the history and schedules a write if change returns true.
+/// The write happens HISTORY_WRITE_DELAY later on a blocking thread, along with any other change made until then.
+fn _history_update(change: impl FnOnce(&mut History) -> bool) -> Result<(), String> {
+    let changed = change(&mut store()?.lock().unwrap_or_else(|e| e.into_inner()));
+    if changed && !WRITE_SCHEDULED.swap(true, Ordering::AcqRel) {
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async {
+                    tokio::time::sleep(HISTORY_WRITE_DELAY).await;
+                    let _ = tokio::task::spawn_blocking(_history_write).await;
+                });
+            },
+            Err(_) => _history_write(),
+        }
+    }
+    Ok(())
+}
+
+/// Writes the changes made to the history since the last write.
+/// The file is written without holding the history's lock, so page loads do not wait for the disk.
+fn _history_write() {
+    // keeps writes in the order they were taken from the history
+    static FILE: Mutex<()> = Mutex::new(());
+    let _file = FILE.lock().unwrap_or_else(|e| e.into_inner());
+    WRITE_SCHEDULED.store(false, Ordering::Release);
+    let Ok(store) = store() else {
+        return;
+    };
+    let (write, path) = {
+        let mut history = store.lock().unwrap_or_else(|e| e.into_inner());
+        (history.take_write(), history.path.clone())
+    };
+    if let Some(Err(e)) = write.map(|write| write.apply(&path)) {
+        println!("Could not save the history: {}", e);
+        // the file may be missing changes now, so write all of it next time
+        store.lock().unwrap_or_else(|e| e.into_inner()).rewrite = true;
+    }
+}
+
+/// Records a visit of url, written without gemini://, that was answered with status
+pub fn history_record(url: &str, status: u8) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    if let Err(e) = _history_update(|history| history.record(url, status, now)) {
+        println!("{}", e);
+    }
+}
+
+/// Sets the title of url, once its first heading arrived
+pub fn history_set_title(url: &str, title: &str) {
+    if let Err(e) = _history_update(|history| history.set_title(url, title)) {
+        println!("{}", e);
+    }
+}
+
+/// See History::search
+pub fn history_search(query: &str) -> Vec<HistoryEntry> {
+    match store() {
+        Ok(history) => history.lock().unwrap_or_else(|e| e.into_inner()).search(query),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn history_delete(url: &str) -> Result<(), String> {
+    _history_update(|history| history.remove(url))
+}
+
+pub fn history_delete_host(host: &str) -> Result<(), String> {
+    _history_update(|history| history.remove_host(host))
+}
+
+pub fn history_clear() -> Result<(), String> {
+    _history_update(|history| {
+        history.clear();
+        true
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dioscuri-history-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join(HISTORY_FILENAME)
+    }
+
+    fn save(history: &mut History) {
+        if let Some(write) = history.take_write() {
+            write.apply(&history.path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_record_and_search() {
+        let mut history = History::load(&temp_path("search")).unwrap();
+        assert!(history.record("example.org/", 20, 100));
+        assert!(history.set_title("example.org/", "Example\tCapsule "));
+        assert!(history.record("other.org:1966/page?q", 51, 200));
+        // revisiting moves the url to the top and keeps its title
+        assert!(history.record("example.org/", 20, 300));
+        assert!(!history.record("example.org/\nbad", 20, 400));
+
+        let all = history.search("");
+        assert_eq!(all.iter().map(|e| e.url.as_str()).collect::<Vec<_>>(), ["example.org/", "other.org:1966/page?q"]);
+        assert_eq!(all[0].title.as_deref(), Some("Example Capsule"));
+        assert_eq!(all[0].visited, 300);
+        assert_eq!(all[1].host(), "other.org:1966");
+        assert_eq!(history.search("CAPSULE").len(), 1);
+        assert_eq!(history.search("other")[0].status, 51);
+        assert!(history.search("missing").is_empty());
+
+        assert!(history.remove("example.org/"));
+        assert!(!history.remove("example.org/"));
+        assert!(history.remove_host("other.org:1966"));
+        assert!(history.search("").is_empty());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = temp_path("roundtrip");
+        let mut history = History::load(&path).unwrap();
+        history.record("example.org/", 20, 100);
+        history.set_title("example.org/", "Example");
+        history.record("example.org/untitled", 20, 200);
+        save(&mut history);
+
+        let loaded = History::load(&path).unwrap();
+        assert_eq!(loaded.entries, history.entries);
+
+        // malformed lines are skipped
+        let mut contents = fs::read_to_string(&path).unwrap();
+        contents.push_str("garbage\n300\t20\n");
+        fs::write(&path, contents).unwrap();
+        assert_eq!(History::load(&path).unwrap().entries, history.entries);
+
+        fs::write(&path, "# dioscuri history v2\n").unwrap();
+        assert!(History::load(&path).is_err());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_appends() {
+        let path = temp_path("appends");
+        let mut history = History::load(&path).unwrap();
+        assert_eq!(history.take_write(), None);
+        history.record("example.org/", 20, 100);
+        // a new file is written whole
+        let write = history.take_write().unwrap();
+        assert!(matches!(write, HistoryWrite::Replace(_)));
+        write.apply(&path).unwrap();
+
+        // a visit and its title are appended as one line
+        history.record("example.org/page", 20, 200);
+        history.set_title("example.org/page", "Page");
+        let write = history.take_write().unwrap();
+        assert_eq!(write, HistoryWrite::Append("200\t20\texample.org/page\tPage\n".to_string()));
+        write.apply(&path).unwrap();
+        // a revisit is appended too, the last line of a url wins
+        history.record("example.org/", 31, 300);
+        save(&mut history);
+        let loaded = History::load(&path).unwrap();
+        assert_eq!(loaded.file_lines, 3);
+        assert_eq!(loaded.entries, history.entries);
+
+        // removing rewrites the file
+        history.remove("example.org/page");
+        assert!(matches!(history.take_write(), Some(HistoryWrite::Replace(_))));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut history = History::load(&temp_path("capacity")).unwrap();
+        for i in 0..=HISTORY_CAPACITY {
+            history.record(&format!("example.org/{i}"), 20, i as i64);
+        }
+        assert_eq!(history.entries.len(), HISTORY_CAPACITY);
+        assert_eq!(history.entries[0].url, "example.org/1");
+    }
+}
@@ -0,0 +1,211 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use native_tls::Identity;
+use rcgen::{CertificateParams, DnType, KeyPair};
+use url::Url;
+
+use crate::{config, gemini::gemini_default_port};
+
+// The identity module manages client certificates for capsules that return status 60-62.
+// Each identity lives in ~/.dioscuri/identities/{name}/ as cert.pem, key.pem and a scopes file.
+// A scope is a host:port/path prefix, e.g. bbs.geminispace.org:1965/ for a whole capsule.
+
+const CERT_FILENAME: &str = "cert.pem";
+const KEY_FILENAME: &str = "key.pem";
+const SCOPES_FILENAME: &str = "scopes";
+
+/// A client certificate and the url prefixes it is presented to
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIdentity {
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+/// Returns true if name can be used as an identity name, i.e. it is non-empty and only has [A-Za-z0-9_-]
+pub fn identity_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Returns the (host, path) scopes a gemini url can be given an identity for:
+/// the whole capsule, and the directory the page is in. The url may omit gemini://
+pub fn identity_scopes_for_url(url: &str) -> Option<(String, String)> {
+    let location = _identity_location(url)?;
+    let host_end = location.find('/').unwrap_or(location.len());
+    let host_scope = format!("{}/", &location[..host_end]);
+    let path_scope = match location.rfind('/') {
+        Some(idx) if idx > host_end => location[..=idx].to_string(),
+        _ => host_scope.clone(),
+    };
+    Some((host_scope, path_scope))
+}
+
+/// Lists all identities, sorted by name
+pub fn identity_list() -> Vec<ClientIdentity> {
+    let Ok(entries) = fs::read_dir(_identity_get_dir()) else {
+        return Vec::new();
+    };
+    let mut identities: Vec<ClientIdentity> = entries.flatten()
+        .filter_map(|entry| entry.file_name().to_str().map(|s| s.to_string()))
+        .filter(|name| identity_valid_name(name))
+        .filter_map(|name| _identity_load(&name))
+        .collect();
+    identities.sort_by(|a, b| a.name.cmp(&b.name));
+    identities
+}
+
+/// Generates a new self-signed client certificate called name, scoped to scope.
+/// Fails if the name is invalid or already taken.
+pub fn identity_create(name: &str, scope: &str) -> Result<(), String> {
+    if !identity_valid_name(name) {
+        return Err("Identity names may only contain letters, digits, '-' and '_'".to_string());
+    }
+    let dir = _identity_get_dir().join(name);
+    if dir.exists() {
+        return Err(format!("An identity called {} already exists", name));
+    }
+    let key_pair = KeyPair::generate().map_err(|e| format!("Could not generate a key: {e}"))?;
+    let mut params = CertificateParams::new(Vec::<String>::new())
+        .map_err(|e| format!("Could not create a certificate: {e}"))?;
+    params.distinguished_name.push(DnType::CommonName, name);
+    let cert = params.self_signed(&key_pair).map_err(|e| format!("Could not sign the certificate: {e}"))?;
+
+    fs::create_dir_all(&dir).map_err(|e| format!("Could not create {:?}: {e}", dir))?;
+    _identity_write_private(&dir.join(KEY_FILENAME), key_pair.serialize_pem().as_bytes())
+        .map_err(|e| format!("Could not write the key: {e}"))?;
+    fs::write(dir.join(CERT_FILENAME), cert.pem()).map_err(|e| format!("Could not write the certificate: {e}"))?;
+    identity_add_scope(name, scope)
+}
+
+/// Lets the identity called name be presented to every url under scope.
+/// A scope belongs to at most one identity, so it is removed from any other identity first.
+pub fn identity_add_scope(name: &str, scope: &str) -> Result<(), String> {
+    let Some(mut identity) = identity_valid_name(name).then(|| _identity_load(name)).flatten() else {
+        return Err(format!("No identity called {}", name));
+    };
+    let Some(scope) = _identity_location(scope) else {
+        return Err(format!("{} is not a valid scope", scope));
+    };
+    for mut other in identity_list().into_iter().filter(|other| other.name != name) {
+        if other.scopes.contains(&scope) {
+            other.scopes.retain(|s| *s != scope);
+            _identity_save_scopes(&other)?;
+        }
+    }
+    if !identity.scopes.contains(&scope) {
+        identity.scopes.push(scope);
+    }
+    _identity_save_scopes(&identity)
+}
+
+/// Returns the TLS identity to present for a gemini url, if any.
+/// When several scopes match, the longest (most specific) one wins.
+/// This reads the identities directory, so async code should call it through spawn_blocking.
+pub fn identity_for_url(url: &str) -> Option<Identity> {
+    let location = _identity_location(url)?;
+    let identity = identity_list().into_iter()
+        .filter_map(|identity| {
+            let best = identity.scopes.iter()
+                .filter(|scope| location.starts_with(scope.as_str()))
+                .map(|scope| scope.len())
+                .max()?;
+            Some((best, identity))
+        })
+        .max_by_key(|(len, _)| *len)?
+        .1;
+    let dir = _identity_get_dir().join(&identity.name);
+    let cert = fs::read(dir.join(CERT_FILENAME)).ok()?;
+    let key = fs::read(dir.join(KEY_FILENAME)).ok()?;
+    match Identity::from_pkcs8(&cert, &key) {
+        Ok(tls_identity) => Some(tls_identity),
+        Err(e) => {
+            println!("Could not load identity {}: {}", identity.name, e);
+            None
+        }
+    }
+}
+
+/// Normalizes a url or scope into host:port/path, with the host lowercased and the port always present.
+/// Queries and fragments are dropped.
+fn _identity_location(url: &str) -> Option<String> {
+    let url = url.trim();
+    let url = match url.split_once("://") {
+        Some(("gemini", rest)) => rest,
+        Some(_) => return None,
+        None => url,
+    };
+    let parsed = Url::parse(&format!("gemini://{}", url)).ok()?;
+    let host = parsed.host_str()?.to_ascii_lowercase();
+    let port = parsed.port().unwrap_or(gemini_default_port());
+    let path = if parsed.path().is_empty() { "/" } else { parsed.path() };
+    Some(format!("{}:{}{}", host, port, path))
+}
+
+fn _identity_load(name: &str) -> Option<ClientIdentity> {
+    let dir = _identity_get_dir().join(name);
+    if !dir.join(CERT_FILENAME).exists() || !dir.join(KEY_FILENAME).exists() {
+        return None;
+    }
+    let scopes = fs::read_to_string(dir.join(SCOPES_FILENAME)).unwrap_or_default()
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect();
+    Some(ClientIdentity { name: name.to_string(), scopes })
+}
+
+fn _identity_save_scopes(identity: &ClientIdentity) -> Result<(), String> {
+    let mut contents = identity.scopes.join("\n");
+    contents.push('\n');
+    fs::write(_identity_get_dir().join(&identity.name).join(SCOPES_FILENAME), contents)
+        .map_err(|e| format!("Could not save the scopes of {}: {e}", identity.name))
+}
+
+/// Writes a file only readable by the current user where the platform supports it
+fn _identity_write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+/// Returns ~/.dioscuri/identities, or identities/ in the configured storage directory
+fn _identity_get_dir() -> PathBuf {
+    config::config_get().identities_dir()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        assert_eq!(_identity_location("gemini://BBS.example.org/u/login?x#y"), Some("bbs.example.org:1965/u/login".to_string()));
+        assert_eq!(_identity_location("bbs.example.org"), Some("bbs.example.org:1965/".to_string()));
+        assert_eq!(_identity_location("bbs.example.org:1966/x"), Some("bbs.example.org:1966/x".to_string()));
+        assert_eq!(_identity_location("https://bbs.example.org/"), None);
+    }
+
+    #[test]
+    fn test_scopes_for_url() {
+        assert_eq!(identity_scopes_for_url("bbs.example.org/u/alice/post"),
+            Some(("bbs.example.org:1965/".to_string(), "bbs.example.org:1965/u/alice/".to_string())));
+        assert_eq!(identity_scopes_for_url("gemini://bbs.example.org:1966/login"),
+            Some(("bbs.example.org:1966/".to_string(), "bbs.example.org:1966/".to_string())));
+        assert_eq!(identity_scopes_for_url("bbs.example.org"),
+            Some(("bbs.example.org:1965/".to_string(), "bbs.example.org:1965/".to_string())));
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(identity_valid_name("alice_bbs-2"));
+        assert!(!identity_valid_name(""));
+        assert!(!identity_valid_name("../alice"));
+        assert!(!identity_valid_name("alice bob"));
+    }
+}
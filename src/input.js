@@ -0,0 +1,26 @@
+// Counts the bytes left for the answer to an input prompt.
+// A gemini request, including the answer, may be at most 1024 bytes. The server puts the bytes left for the
+// answer in data-max-bytes. The answer is sent percent encoded, so each byte that is not plain ascii counts three times.
+function dioscuriEncodedLength(value) {
+    return encodeURIComponent(value).replace(/[!'()*]/g, "%00").length;
+}
+
+document.querySelectorAll("form[data-max-bytes]").forEach(function (form) {
+    var field = form.querySelector("[name=dioscuri_input]");
+    var counter = form.querySelector(".dioscuri-input-counter");
+    var max = parseInt(form.dataset.maxBytes, 10);
+    if (!field || isNaN(max)) {
+        return;
+    }
+    function update() {
+        var left = max - dioscuriEncodedLength(field.value);
+        var over = left < 0;
+        field.setCustomValidity(over ? "This answer is " + (-left) + " bytes too long for a gemini request." : "");
+        form.classList.toggle("dioscuri-input-over", over);
+        if (counter) {
+            counter.textContent = over ? (-left) + " bytes too long" : left + " bytes left";
+        }
+    }
+    field.addEventListener("input", update);
+    update();
+});
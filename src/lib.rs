@@ -0,0 +1,31 @@
+//! Dioscuri is a gemini client that runs as a local http proxy, so gemini pages can be read in any web browser.
+//!
+//! The pieces it is made of can be used on their own:
+//! - [`gemini`]: the client, see [`gemini::GeminiClient`], and the response types it returns
+//! - [`gemtext`]: the gemtext parser and the html renderer
+//! - [`tofu`]: the known_hosts trust store certificates are checked against
+//! - [`browser`]: the proxy itself, as an axum [`Router`](axum::Router) from [`browser::browser_router`]
+//! - [`config`]: the configuration, which most modules read through [`config::config_get`]
+//! - [`theme`]: the themes the proxy renders pages with
+//!
+//! Storage (the trust store, identities, themes and the browsing history) lives in the directories of the process wide configuration.
+//! Call [`config::config_init`] first to use another configuration than the defaults.
+//!
+//! ```
+//! use dioscuri::gemtext::{parse_gemtext, GemtextLine};
+//!
+//! let lines = parse_gemtext("# Hello\n=> gemini://example.org/ Example");
+//! assert_eq!(lines[0], GemtextLine::Heading { level: 1, text: "Hello".to_string() });
+//! ```
+
+pub mod browser;
+pub mod config;
+pub mod gemini;
+pub mod gemtext;
+pub mod theme;
+pub mod tofu;
+
+mod cache;
+mod history;
+mod identity;
+mod resource;
@@ -1,14 +1,52 @@
-use std::io::{self};
+use dioscuri::browser::start_browser;
+use dioscuri::config::{self, CliAction};
+use dioscuri::{theme, tofu};
 
-use crate::browser::start_browser;
-
-mod gemini;
-mod tofu;
-mod browser;
-mod gemtext;
-
-// fn main() -> io::Result<()> {
 fn main() {
-    println!("Welcome to Project Dioscuri!\nAccess gemini by opening http://localhost:1965/ on any web browser!");
-    start_browser();
+    let config = match config::config_from_args(std::env::args().skip(1)) {
+        Ok(CliAction::Run { config, overrides, path }) => {
+            config::config_init(path, *config);
+            config::config_override(overrides);
+            (*config::config_get()).clone()
+        },
+        Ok(CliAction::ThemeList { config, overrides, path }) => {
+            config::config_init(path, *config);
+            config::config_override(overrides);
+            let installed = theme::theme_list_installed();
+            for name in theme::theme_list() {
+                match (installed.contains(&name), theme::theme_embedded(&name).is_some()) {
+                    (true, true) => println!("{name} (installed, built in)"),
+                    (true, false) => println!("{name} (installed)"),
+                    _ => println!("{name} (built in)"),
+                }
+            }
+            return;
+        },
+        Ok(CliAction::ThemeInstall { config, overrides, path, name, force }) => {
+            config::config_init(path, *config);
+            config::config_override(overrides);
+            let addr = config::config_get().network.socket_addr();
+            match theme::theme_install(&name, force) {
+                Ok(dir) => println!("Installed {name} to {:?}. Pick it on http://{addr}/.dioscuri/themes", dir),
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            }
+            return;
+        },
+        Ok(CliAction::Help) => {
+            println!("{}", config::USAGE);
+            return;
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+    tofu::tofu_migrate_legacy_store();
+    if let Err(e) = start_browser(config) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,305 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{body::Body, http::{self, HeaderMap, HeaderValue}, response::Response};
+use sha2::{Digest, Sha256};
+
+use crate::theme::ThemeFile;
+
+// The resource module turns theme files into http responses for /.src.
+// It sets the Content-Type from the file extension, lets browsers revalidate with ETag and Last-Modified,
+// and serves byte ranges of larger files such as fonts.
+
+/// Served for extensions that are not in MIME_TYPES
+const DEFAULT_MIME: &str = "application/octet-stream";
+
+/// Content types by lowercase file extension
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("gmi", "text/gemini; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("wasm", "application/wasm"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("ico", "image/x-icon"),
+    ("cur", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("eot", "application/vnd.ms-fontobject"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("pdf", "application/pdf"),
+];
+
+/// Returns the content type for a file path based on its extension
+pub fn resource_mime(path: &str) -> &'static str {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let Some((_, extension)) = file_name.rsplit_once('.') else {
+        return DEFAULT_MIME;
+    };
+    MIME_TYPES.iter()
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map(|(_, mime)| *mime)
+        .unwrap_or(DEFAULT_MIME)
+}
+
+/// Builds the response for a theme file at path, honouring the conditional and range headers of the request.
+/// Theme files are served with no-cache: the same url can belong to another theme after switching,
+/// so browsers revalidate every time and usually get a 304 back.
+pub fn resource_response(path: &str, file: ThemeFile, request: &HeaderMap) -> Response {
+    let etag = resource_etag(&file.contents);
+    // http dates have a resolution of seconds
+    let modified = file.modified.map(_resource_truncate_to_seconds);
+
+    let mut builder = Response::builder()
+        .header(http::header::ETAG, &etag)
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .header(http::header::VARY, "Cookie")
+        .header(http::header::ACCEPT_RANGES, "bytes")
+        .header(http::header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+    if let Some(modified) = modified {
+        builder = builder.header(http::header::LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+
+    if _resource_not_modified(request, &etag, modified) {
+        return builder.status(http::StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap();
+    }
+
+    builder = builder.header(http::header::CONTENT_TYPE, resource_mime(path));
+    let len = file.contents.len() as u64;
+    let range = request.get(http::header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| _resource_if_range_matches(request, &etag, modified));
+    match range.map(|range| resource_parse_range(range, len)) {
+        Some(RangeRequest::Satisfiable(start, end)) => builder
+            .status(http::StatusCode::PARTIAL_CONTENT)
+            .header(http::header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len))
+            .body(Body::from(file.contents[start as usize..=end as usize].to_vec()))
+            .unwrap(),
+        Some(RangeRequest::Unsatisfiable) => builder
+            .status(http::StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(http::header::CONTENT_RANGE, format!("bytes */{}", len))
+            .body(Body::empty())
+            .unwrap(),
+        Some(RangeRequest::Ignored) | None => builder
+            .status(http::StatusCode::OK)
+            .body(Body::from(file.contents))
+            .unwrap(),
+    }
+}
+
+/// A strong ETag derived from the contents, so the same file in two themes shares a tag
+/// and an edited file gets a new one without relying on modification times
+pub fn resource_etag(contents: &[u8]) -> String {
+    let digest = Sha256::digest(contents);
+    let hex: String = digest.iter().take(16).map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+/// How a Range header applies to a resource
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// Serve bytes start..=end
+    Satisfiable(u64, u64),
+    /// Answer with 416
+    Unsatisfiable,
+    /// Serve the whole resource, e.g. for multiple ranges or units other than bytes
+    Ignored,
+}
+
+/// Parses a Range header for a resource of len bytes. Only a single byte range is supported:
+/// `bytes=start-end`, `bytes=start-` and `bytes=-suffix_length`.
+pub fn resource_parse_range(header: &str, len: u64) -> RangeRequest {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Ignored;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Ignored;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Ignored;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=-n is the last n bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return RangeRequest::Unsatisfiable;
+            }
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        },
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        _ => return RangeRequest::Ignored,
+    };
+    if len == 0 || range.0 >= len {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Satisfiable(range.0, range.1)
+}
+
+/// True if the request's If-None-Match (or, without it, If-Modified-Since) says the browser's copy is current
+fn _resource_not_modified(request: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = request.get(http::header::IF_NONE_MATCH) {
+        return if_none_match.to_str().is_ok_and(|tags| _resource_etag_listed(tags, etag));
+    }
+    match (request.get(http::header::IF_MODIFIED_SINCE).and_then(_resource_parse_date), modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// True if a Range request should be honoured: there is no If-Range, or it names the current ETag or date
+fn _resource_if_range_matches(request: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    let Some(if_range) = request.get(http::header::IF_RANGE) else {
+        return true;
+    };
+    if let Some(date) = _resource_parse_date(if_range) {
+        return modified == Some(date);
+    }
+    if_range.to_str().is_ok_and(|tag| tag.trim() == etag)
+}
+
+/// True if a comma separated list of entity tags from If-None-Match contains etag or is *.
+/// Weak tags (W/"...") match their strong counterpart, as If-None-Match uses weak comparison.
+fn _resource_etag_listed(tags: &str, etag: &str) -> bool {
+    tags.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn _resource_parse_date(value: &HeaderValue) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.to_str().ok()?).ok()
+}
+
+fn _resource_truncate_to_seconds(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => UNIX_EPOCH + std::time::Duration::from_secs(since_epoch.as_secs()),
+        Err(_) => time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn file(contents: &[u8], modified: Option<SystemTime>) -> ThemeFile {
+        ThemeFile { contents: contents.to_vec(), modified }
+    }
+
+    fn request(headers: &[(http::header::HeaderName, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_mime() {
+        assert_eq!(resource_mime("style.css"), "text/css; charset=utf-8");
+        assert_eq!(resource_mime("scripts/addressbar.JS"), "text/javascript; charset=utf-8");
+        assert_eq!(resource_mime("fonts/comic.woff2"), "font/woff2");
+        assert_eq!(resource_mime("img/cat.v2.png"), "image/png");
+        assert_eq!(resource_mime("LICENSE"), DEFAULT_MIME);
+        assert_eq!(resource_mime("dir.css/file"), DEFAULT_MIME);
+        assert_eq!(resource_mime("archive.xyz"), DEFAULT_MIME);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(resource_parse_range("bytes=0-3", 10), RangeRequest::Satisfiable(0, 3));
+        assert_eq!(resource_parse_range("bytes=5-", 10), RangeRequest::Satisfiable(5, 9));
+        assert_eq!(resource_parse_range("bytes=-4", 10), RangeRequest::Satisfiable(6, 9));
+        assert_eq!(resource_parse_range("bytes=-40", 10), RangeRequest::Satisfiable(0, 9));
+        assert_eq!(resource_parse_range("bytes=8-100", 10), RangeRequest::Satisfiable(8, 9));
+        assert_eq!(resource_parse_range("bytes=10-", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(resource_parse_range("bytes=-0", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(resource_parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(resource_parse_range("bytes=5-2", 10), RangeRequest::Ignored);
+        assert_eq!(resource_parse_range("bytes=0-1,4-5", 10), RangeRequest::Ignored);
+        assert_eq!(resource_parse_range("items=0-1", 10), RangeRequest::Ignored);
+    }
+
+    #[tokio::test]
+    async fn test_full_response() {
+        let modified = UNIX_EPOCH + Duration::from_millis(1_798_761_600_500);
+        let response = resource_response("style.css", file(b"body {}", Some(modified)), &HeaderMap::new());
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "text/css; charset=utf-8");
+        assert_eq!(response.headers()[http::header::ETAG], resource_etag(b"body {}"));
+        assert_eq!(response.headers()[http::header::LAST_MODIFIED], "Fri, 01 Jan 2027 00:00:00 GMT");
+        assert_eq!(body(response).await, b"body {}");
+
+        // built in files have no modification time
+        let response = resource_response("theme.js", file(b"x", None), &HeaderMap::new());
+        assert!(response.headers().get(http::header::LAST_MODIFIED).is_none());
+    }
+
+    #[test]
+    fn test_conditional_requests() {
+        let etag = resource_etag(b"body {}");
+        let modified = UNIX_EPOCH + Duration::from_secs(1_798_761_600);
+        let status = |headers: &[(http::header::HeaderName, &str)]| {
+            resource_response("style.css", file(b"body {}", Some(modified)), &request(headers)).status()
+        };
+        assert_eq!(status(&[(http::header::IF_NONE_MATCH, &etag)]), http::StatusCode::NOT_MODIFIED);
+        assert_eq!(status(&[(http::header::IF_NONE_MATCH, &format!("\"other\", W/{}", etag))]), http::StatusCode::NOT_MODIFIED);
+        assert_eq!(status(&[(http::header::IF_NONE_MATCH, "*")]), http::StatusCode::NOT_MODIFIED);
+        assert_eq!(status(&[(http::header::IF_NONE_MATCH, "\"other\"")]), http::StatusCode::OK);
+        assert_eq!(status(&[(http::header::IF_MODIFIED_SINCE, "Fri, 01 Jan 2027 00:00:00 GMT")]), http::StatusCode::NOT_MODIFIED);
+        assert_eq!(status(&[(http::header::IF_MODIFIED_SINCE, "Thu, 31 Dec 2026 23:59:59 GMT")]), http::StatusCode::OK);
+        // If-None-Match takes precedence over If-Modified-Since
+        assert_eq!(status(&[
+            (http::header::IF_NONE_MATCH, "\"other\""),
+            (http::header::IF_MODIFIED_SINCE, "Fri, 01 Jan 2027 00:00:00 GMT"),
+        ]), http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        let contents = b"0123456789";
+        let etag = resource_etag(contents);
+        let response = resource_response("font.woff2", file(contents, None), &request(&[(http::header::RANGE, "bytes=2-4")]));
+        assert_eq!(response.status(), http::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[http::header::CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(body(response).await, b"234");
+
+        let response = resource_response("font.woff2", file(contents, None), &request(&[(http::header::RANGE, "bytes=20-")]));
+        assert_eq!(response.status(), http::StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[http::header::CONTENT_RANGE], "bytes */10");
+
+        // a stale If-Range gets the whole file
+        let response = resource_response("font.woff2", file(contents, None), &request(&[
+            (http::header::RANGE, "bytes=2-4"), (http::header::IF_RANGE, "\"stale\""),
+        ]));
+        assert_eq!(response.status(), http::StatusCode::OK);
+        let response = resource_response("font.woff2", file(contents, None), &request(&[
+            (http::header::RANGE, "bytes=-3"), (http::header::IF_RANGE, &etag),
+        ]));
+        assert_eq!(body(response).await, b"789");
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::config;
+
+// The theme module finds the installed themes and decides which one a request is rendered with.
+// Each theme is a directory in ~/.dioscuri/themes/{name}/ holding head.html, body.html, home.html and its resources.
+// The themes in the repo's themes/ directory are also compiled into the binary, so they work without being installed.
+// Files in an installed theme override the built in ones with the same path.
+// ~/.dioscuri/browser, where themes were copied to before, is still used when no theme is selected.
+
+/// Cookie holding the theme picked for one browser
+pub const THEME_COOKIE: &str = "dioscuri_theme";
+/// The built in theme used when no theme is picked and ~/.dioscuri/browser is empty
+const DEFAULT_EMBEDDED_THEME: &str = "modern";
+/// Files that make ~/.dioscuri/browser a customised default theme rather than an empty directory
+const LAYOUT_FILENAMES: [&str; 3] = ["head.html", "body.html", "home.html"];
+
+/// A theme compiled into the binary
+#[derive(Debug, PartialEq)]
+pub struct EmbeddedTheme {
+    pub name: &'static str,
+    /// (path relative to the theme directory, contents)
+    pub files: &'static [(&'static str, &'static [u8])],
+}
+
+macro_rules! embed_theme {
+    ($name:literal, [$($file:literal),* $(,)?]) => {
+        EmbeddedTheme {
+            name: $name,
+            files: &[$(($file, include_bytes!(concat!("../themes/", $name, "/", $file)))),*],
+        }
+    };
+}
+
+/// The themes shipped with Dioscuri, from the repo's themes/ directory
+pub static EMBEDDED_THEMES: &[EmbeddedTheme] = &[
+    embed_theme!("modern", ["addressbar.js", "body.html", "head.html", "home.html", "style.css", "theme.js"]),
+    embed_theme!("newspaper", ["addressbar.js", "body.html", "head.html", "home.html", "style.css"]),
+    embed_theme!("nyan", ["addressbar.js", "body.html", "cursor.js", "head.html", "home.html", "style.css"]),
+];
+
+/// The theme a request is rendered with
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveTheme {
+    /// None for the default theme in ~/.dioscuri/browser
+    pub name: Option<String>,
+    /// Where the theme's files are looked up first. It does not have to exist for built in themes.
+    pub dir: PathBuf,
+    /// The built in files used when dir does not have them
+    pub embedded: Option<&'static EmbeddedTheme>,
+}
+
+/// A file read from a theme
+#[derive(Debug, PartialEq)]
+pub struct ThemeFile {
+    pub contents: Vec<u8>,
+    /// Modification time of an installed file. Built in files have none.
+    pub modified: Option<SystemTime>,
+}
+
+/// Why a theme file could not be read
+#[derive(Debug, PartialEq)]
+pub enum ThemeFileError {
+    NotFound,
+    /// The path leads outside the theme directory
+    Forbidden,
+}
+
+impl ActiveTheme {
+    /// Reads the file at path, relative to the theme directory.
+    /// Paths that resolve outside the theme directory, e.g. through .. or symlinks, are refused.
+    pub fn read(&self, path: &str) -> Result<ThemeFile, ThemeFileError> {
+        if let (Ok(file), Ok(dir)) = (self.dir.join(path).canonicalize(), self.dir.canonicalize()) {
+            // resource should be a child of the theme folder
+            if !file.starts_with(&dir) {
+                return Err(ThemeFileError::Forbidden);
+            }
+            if let Ok(contents) = fs::read(&file) {
+                let modified = fs::metadata(&file).and_then(|meta| meta.modified()).ok();
+                return Ok(ThemeFile { contents, modified });
+            }
+        }
+        self.embedded
+            .and_then(|theme| theme.files.iter().find(|(name, _)| *name == path))
+            .map(|(_, contents)| ThemeFile { contents: contents.to_vec(), modified: None })
+            .ok_or(ThemeFileError::NotFound)
+    }
+
+    /// Reads the file at path as text, or returns None if it does not exist
+    pub fn read_to_string(&self, path: &str) -> Option<String> {
+        self.read(path).ok().map(|file| String::from_utf8_lossy(&file.contents).into_owned())
+    }
+}
+
+/// Returns true if name can be used as a theme name, i.e. it is non-empty and only has [A-Za-z0-9_-]
+pub fn theme_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Returns the built in theme called name, if any
+pub fn theme_embedded(name: &str) -> Option<&'static EmbeddedTheme> {
+    EMBEDDED_THEMES.iter().find(|theme| theme.name == name)
+}
+
+/// Lists the names of the installed themes, sorted
+pub fn theme_list_installed() -> Vec<String> {
+    let Ok(entries) = fs::read_dir(theme_get_themes_dir()) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries.flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().to_str().map(|s| s.to_string()))
+        .filter(|name| theme_valid_name(name))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Lists the names of every theme that can be used, installed or built in, sorted
+pub fn theme_list() -> Vec<String> {
+    let mut names = theme_list_installed();
+    names.extend(EMBEDDED_THEMES.iter().map(|theme| theme.name.to_string()));
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Returns the theme called name, if it is installed or built in
+pub fn theme_get(name: &str) -> Option<ActiveTheme> {
+    if !theme_valid_name(name) {
+        return None;
+    }
+    let dir = theme_get_themes_dir().join(name);
+    let embedded = theme_embedded(name);
+    (dir.is_dir() || embedded.is_some()).then(|| ActiveTheme { name: Some(name.to_string()), dir, embedded })
+}
+
+/// Picks the theme for a request: the one named by the cookie, else theme.name from the config,
+/// else the default theme. Names of themes that do not exist are ignored.
+/// The default theme is ~/.dioscuri/browser if a layout was copied there, else the built in modern theme.
+pub fn theme_active(cookie: Option<&str>) -> ActiveTheme {
+    let configured = config::config_get().theme.name.clone();
+    cookie.map(|name| name.to_string())
+        .into_iter()
+        .chain(configured)
+        .find_map(|name| theme_get(&name))
+        .unwrap_or_else(|| {
+            let dir = theme_get_default_dir();
+            let customised = LAYOUT_FILENAMES.iter().any(|file| dir.join(file).is_file());
+            let embedded = if customised { None } else { theme_embedded(DEFAULT_EMBEDDED_THEME) };
+            ActiveTheme { name: None, dir, embedded }
+        })
+}
+
+/// Writes the built in theme called name to ~/.dioscuri/themes/{name}/ so it can be customised.
+/// An existing directory is only overwritten if force is set, and only the built in files are replaced.
+/// Returns the directory the theme was written to.
+pub fn theme_install(name: &str, force: bool) -> Result<PathBuf, String> {
+    let Some(theme) = theme_embedded(name) else {
+        let names: Vec<&str> = EMBEDDED_THEMES.iter().map(|theme| theme.name).collect();
+        return Err(format!("There is no built in theme called {}. Built in themes: {}", name, names.join(", ")));
+    };
+    let dir = theme_get_themes_dir().join(name);
+    if dir.exists() && !force {
+        return Err(format!("{:?} already exists. Use --force to overwrite its files with the built in ones.", dir));
+    }
+    for (path, contents) in theme.files {
+        let file = dir.join(path);
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Could not create {:?}: {e}", parent))?;
+        }
+        fs::write(&file, contents).map_err(|e| format!("Could not write {:?}: {e}", file))?;
+    }
+    Ok(dir)
+}
+
+/// Creates the themes directory and the default theme directory if they do not exist
+pub fn theme_setup_directories() {
+    for dir in [theme_get_themes_dir(), theme_get_default_dir()] {
+        if !dir.exists() {
+            match fs::create_dir_all(&dir) {
+                Ok(()) => println!("Creating directory: {:?}", dir),
+                Err(e) => println!("Could not create {:?}: {}", dir, e),
+            }
+        }
+    }
+}
+
+/// Returns ~/.dioscuri/themes, or theme.directory from the config
+pub fn theme_get_themes_dir() -> PathBuf {
+    config::config_get().themes_dir()
+}
+
+/// Returns ~/.dioscuri/browser, the theme used when none is selected
+pub fn theme_get_default_dir() -> PathBuf {
+    config::config_get().storage_dir().join("browser")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_valid_name() {
+        assert!(theme_valid_name("modern"));
+        assert!(theme_valid_name("my_theme-2"));
+        assert!(!theme_valid_name(""));
+        assert!(!theme_valid_name(".."));
+        assert!(!theme_valid_name("a/b"));
+    }
+
+    #[test]
+    fn test_active_theme() {
+        let storage = std::env::temp_dir().join(format!("dioscuri-theme-test-{}", std::process::id()));
+        let mut config = Config::default();
+        config.storage.directory = Some(storage.clone());
+        config.theme.name = Some("custom".to_string());
+        config::config_init(storage.join("config.toml"), config);
+        fs::create_dir_all(storage.join("themes/custom")).unwrap();
+        fs::create_dir_all(storage.join("browser")).unwrap();
+
+        assert_eq!(theme_list(), vec!["custom", "modern", "newspaper", "nyan"]);
+        // the cookie wins over the config, unknown names are ignored
+        assert_eq!(theme_active(Some("nyan")).name.as_deref(), Some("nyan"));
+        assert_eq!(theme_active(Some("missing")).name.as_deref(), Some("custom"));
+        assert_eq!(theme_active(Some("../browser")).name.as_deref(), Some("custom"));
+        assert_eq!(theme_active(None).dir, storage.join("themes/custom"));
+
+        // without a theme, an empty ~/.dioscuri/browser falls back to the built in default
+        fs::remove_dir_all(storage.join("themes/custom")).unwrap();
+        let default = theme_active(None);
+        assert_eq!((default.name, default.dir), (None, storage.join("browser")));
+        assert_eq!(default.embedded.map(|theme| theme.name), Some(DEFAULT_EMBEDDED_THEME));
+        fs::write(storage.join("browser/body.html"), "<Dioscuri/>").unwrap();
+        assert_eq!(theme_active(None).embedded, None);
+
+        // installing exports the built in files, and installed files override them
+        assert!(theme_install("missing", false).is_err());
+        let dir = theme_install("nyan", false).unwrap();
+        assert!(theme_install("nyan", false).is_err());
+        fs::write(dir.join("style.css"), "body {}").unwrap();
+        fs::remove_file(dir.join("head.html")).unwrap();
+        let nyan = theme_active(Some("nyan"));
+        let style = nyan.read("style.css").unwrap();
+        assert_eq!(style.contents, b"body {}".to_vec());
+        assert!(style.modified.is_some());
+        assert!(nyan.read_to_string("head.html").is_some_and(|head| head.starts_with("<head>")));
+        assert_eq!(nyan.read("head.html").unwrap().modified, None);
+        assert_eq!(nyan.read("missing.css"), Err(ThemeFileError::NotFound));
+        assert_eq!(nyan.read("../../browser/body.html"), Err(ThemeFileError::Forbidden));
+        theme_install("nyan", true).unwrap();
+        assert_ne!(nyan.read("style.css").unwrap().contents, b"body {}".to_vec());
+        fs::remove_dir_all(&storage).unwrap();
+    }
+}
@@ -1,96 +1,668 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use native_tls::Certificate;
-use x509_parser::prelude::{FromDer, X509Certificate};
-
-/// Checks a certificate given in a TLS stream with the certificate store.
-/// If the certificate exists and is not expired, then accept it
-/// If the certificate exists and is expired but the PK is different, abort
-/// If the certificate !exist, then add it to the certificate store
-/// Checks a certificate given in a TLS stream with the certificate store.
-/// If the certificate exists and is not expired, then accept it
-/// If the certificate exists and is expired but the PK is different, abort
-/// If the certificate !exist, then add it to the certificate store
-pub fn tofu_handle_certificate(cert: Certificate) -> Result<(), ()> {
-    let cert_dir = _tofu_get_cert_dir();
-    let cert_der = cert.to_der().unwrap();
-    let res = X509Certificate::from_der(&cert_der);
-    match res {
-        Ok((_, target_cert)) => {
-            if !target_cert.validity().is_valid() {
-                return Err(())
+use sha2::{Digest, Sha256};
+use x509_parser::prelude::{ASN1Time, FromDer, X509Certificate};
+
+use crate::{config, gemini::DEFAULT_PORT};
+
+const KNOWN_HOSTS_FILENAME: &str = "known_hosts";
+const KNOWN_HOSTS_VERSION: u32 = 1;
+const KNOWN_HOSTS_HEADER: &str = "# dioscuri known_hosts v";
+/// How long a change to last seen alone may wait before known_hosts is written
+const LAST_SEEN_SAVE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Summary of a certificate, shown to the user when trust cannot be established automatically
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertInfo {
+    /// SHA-256 of the certificate's SubjectPublicKeyInfo, lowercase hex
+    pub fingerprint: String,
+    pub not_after: String,
+}
+
+/// Reasons why a certificate was not trusted
+#[derive(Debug, Clone, PartialEq)]
+pub enum TofuError {
+    /// The certificate store holds a different public key for this key
+    Mismatch { key: String, old: CertInfo, new: CertInfo },
+    /// The presented certificate is expired, or not yet valid if not_yet_valid is set
+    Expired { key: String, new: CertInfo, not_yet_valid: bool },
+    /// The certificate could not be parsed, or the store could not be used
+    Invalid(String),
+}
+
+/// A trusted host in the known_hosts file.
+/// Timestamps are seconds since the unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownHost {
+    /// host:port, see tofu_key
+    pub key: String,
+    /// SHA-256 of the certificate's SubjectPublicKeyInfo, lowercase hex
+    pub fingerprint: String,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    /// Expiry of the last certificate seen with this public key
+    pub not_after: i64,
+}
+
+/// The trust database, a single versioned file with one line per host:
+/// `{host:port} {fingerprint} {first_seen} {last_seen} {not_after}`
+/// Blank lines and lines starting with # are ignored, except for the version header on the first line.
+#[derive(Debug)]
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: BTreeMap<String, KnownHost>,
+    /// When last seen was first updated without writing the file, see tofu_handle_certificate
+    unsaved_since: Option<Instant>,
+}
+
+impl KnownHosts {
+    /// Loads the database at path. A missing file is an empty database.
+    /// Malformed lines and unknown versions are errors rather than skipped,
+    /// as silently dropping an entry would let an impersonator be trusted on first use.
+    pub fn load(path: &Path) -> Result<KnownHosts, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Could not read {:?}: {}", path, e)),
+        };
+        let mut hosts = BTreeMap::new();
+        for (idx, line) in contents.lines().enumerate() {
+            if let Some(version) = line.strip_prefix(KNOWN_HOSTS_HEADER) {
+                if idx == 0 && version.trim() != KNOWN_HOSTS_VERSION.to_string() {
+                    return Err(format!("{:?} has unsupported version {}", path, version.trim()));
+                }
+                continue;
             }
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let host = Self::parse_line(line)
+                .ok_or_else(|| format!("{:?} line {} is malformed", path, idx + 1))?;
+            hosts.insert(host.key.clone(), host);
+        }
+        Ok(KnownHosts { path: path.to_path_buf(), hosts, unsaved_since: None })
+    }
 
-            let subject_cn = target_cert.subject().iter_common_name().next().unwrap();
-            let domain_str = subject_cn.as_str().unwrap();
-            let target_cert_path = cert_dir.join(format!("{}.der", domain_str));
-            // println!("Certificate for domain: {}", domain_str);
-            // Search cert store
-            if target_cert_path.exists() {
-                // println!("Certificate found in certificate store!");
-
-                let src_cert_der = fs::read(&target_cert_path).unwrap();
-                let (_, src_cert) = X509Certificate::from_der(&src_cert_der).unwrap();
-                
-                // If the source certificate is valid, check the public keys
-                if src_cert.validity().is_valid() {
-                    let src_pub_key = src_cert.public_key();
-                    let target_pub_key = target_cert.public_key();
-
-                    if src_pub_key == target_pub_key {
-                        // println!("Certificate is valid and public keys match.");
-                        return Ok(()); // Accept if the certificate is valid and public keys match
-                    } else {
-                        // println!("Public keys do not match. Aborting trust.");
-                        return Err(()); // Abort if the public keys do not match
-                    }
-                } else {
-                    // Source certificate is expired, update if public keys match
-                    let src_pub_key = src_cert.public_key();
-                    let target_pub_key = target_cert.public_key();
-
-                    if src_pub_key == target_pub_key {
-                        // println!("Source certificate expired but public keys match. Updating certificate.");
-                        fs::write(&target_cert_path, &cert_der).unwrap();
-                        return Ok(());
-                    } else {
-                        // println!("Source certificate expired but public keys do not match. Abort!");
-                        return Err(());
-                    }
+    fn parse_line(line: &str) -> Option<KnownHost> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [key, fingerprint, first_seen, last_seen, not_after] = fields[..] else {
+            return None;
+        };
+        if !key.contains(':') || fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(KnownHost {
+            key: key.to_string(),
+            fingerprint: fingerprint.to_ascii_lowercase(),
+            first_seen: first_seen.parse().ok()?,
+            last_seen: last_seen.parse().ok()?,
+            not_after: not_after.parse().ok()?,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&KnownHost> {
+        self.hosts.get(key)
+    }
+
+    /// Adds or replaces the entry for host.key. Call save to persist it.
+    pub fn insert(&mut self, host: KnownHost) {
+        self.hosts.insert(host.key.clone(), host);
+    }
+
+    /// Writes the database atomically: the new contents go to a temporary file in the same directory,
+    /// which is then renamed over the old file, so readers never see a partial write.
+    pub fn save(&self) -> std::io::Result<()> {
+        let mut contents = format!("{}{}\n", KNOWN_HOSTS_HEADER, KNOWN_HOSTS_VERSION);
+        contents.push_str("# host:port sha256-spki-fingerprint first-seen last-seen not-after (unix seconds)\n");
+        for host in self.hosts.values() {
+            contents.push_str(&format!("{} {} {} {} {}\n",
+                host.key, host.fingerprint, host.first_seen, host.last_seen, host.not_after));
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = self.path.with_extension(format!("{}.tmp", std::process::id()));
+        let mut tmp = fs::File::create(&tmp_path)?;
+        tmp.write_all(contents.as_bytes())?;
+        tmp.sync_all()?;
+        drop(tmp);
+        fs::rename(&tmp_path, &self.path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp_path);
+        })
+    }
+}
+
+/// The process wide trust database, loaded from ~/.dioscuri/known_hosts (or tofu.known_hosts) on first use.
+/// The mutex serializes every read-modify-write, so concurrent requests cannot lose each other's updates.
+fn store() -> Result<&'static Mutex<KnownHosts>, TofuError> {
+    static STORE: OnceLock<Result<Mutex<KnownHosts>, String>> = OnceLock::new();
+    STORE.get_or_init(|| KnownHosts::load(&_tofu_get_known_hosts_path()).map(Mutex::new))
+        .as_ref()
+        .map_err(|e| TofuError::Invalid(e.clone()))
+}
+
+/// Certificates that failed verification, waiting for the user to decide on them.
+/// Keyed by the store key, holding the error shown to the user and the entry to trust if the user accepts.
+type PendingMap = HashMap<String, (TofuError, KnownHost)>;
+
+fn pending() -> &'static Mutex<PendingMap> {
+    static PENDING: OnceLock<Mutex<PendingMap>> = OnceLock::new();
+    PENDING.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// (key, fingerprint) pairs the user chose to trust until Dioscuri exits
+fn trusted_once() -> &'static Mutex<HashSet<(String, String)>> {
+    static TRUSTED_ONCE: OnceLock<Mutex<HashSet<(String, String)>>> = OnceLock::new();
+    TRUSTED_ONCE.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// Checks a certificate given in a TLS stream with the known_hosts database.
+/// The database is keyed by the host and port that were connected to, never by the certificate's own names,
+/// so a capsule cannot claim another host's entry.
+/// If the host is known and the public key fingerprint matches, accept it and record when it was last seen
+/// If the host is known and the fingerprint is different, abort
+/// If the host is unknown, then add it to the database
+/// Certificates the user chose to trust once are accepted without touching the database.
+/// On failure, the presented certificate is kept as pending so the user can decide on it.
+/// New and renewed certificates are written right away. Updates to last seen alone are written
+/// at most every LAST_SEEN_SAVE_INTERVAL, along with the next write.
+/// This may write to disk, so async code should call it through spawn_blocking.
+pub fn tofu_handle_certificate(cert: Certificate, host: &str, port: u16) -> Result<(), TofuError> {
+    let cert_der = cert.to_der()
+        .map_err(|e| TofuError::Invalid(format!("Certificate could not be encoded: {e}")))?;
+    let mut known_hosts = store()?.lock().unwrap_or_else(|e| e.into_inner());
+    let check_expiry = config::config_get().tofu.check_expiry;
+    match _tofu_check(&mut known_hosts, &cert_der, &tofu_key(host, port), _tofu_now(), check_expiry)? {
+        TofuUpdate::Unchanged => {},
+        TofuUpdate::Seen => {
+            if known_hosts.unsaved_since.get_or_insert_with(Instant::now).elapsed() >= LAST_SEEN_SAVE_INTERVAL {
+                match known_hosts.save() {
+                    Ok(()) => known_hosts.unsaved_since = None,
+                    Err(e) => println!("Could not write known_hosts: {e}"),
                 }
-            }else{
-                // println!("Certificate does not exist in the store. Adding to trust store (TOFU)...");
-                fs::create_dir_all(&cert_dir).unwrap();
-                fs::write(&target_cert_path, &cert_der).unwrap();
-                // println!("New certificate for {} added to trust store.", domain_str);
-                return Ok(()); // Add and accept the new certificate
             }
         },
-        Err(_) => {
-            // println!("Error: Certificate from server is invalid.");
-            Err(()) // Return error if certificate parsing failed
+        TofuUpdate::Trusted => {
+            known_hosts.save()
+                .map_err(|e| TofuError::Invalid(format!("Could not write known_hosts: {e}")))?;
+            known_hosts.unsaved_since = None;
+        },
+    }
+    Ok(())
+}
+
+/// Returns the store key for a host and port, e.g. example.org:1965
+pub fn tofu_key(host: &str, port: u16) -> String {
+    format!("{}:{}", host.to_ascii_lowercase(), port)
+}
+
+/// Imports the per-certificate store of older versions of Dioscuri (~/.dioscuri/cert/*.der) into known_hosts.
+/// Those versions named each file after the certificate's common name and only spoke to the default port,
+/// so {cn}.der is trusted for {cn}:1965. Wildcard common names do not say which host they were seen on and are skipped.
+/// Once everything is imported, the directory is renamed to cert.migrated so it is not imported again.
+/// If a file was skipped the directory is kept, so nothing is lost.
+pub fn tofu_migrate_legacy_store() {
+    let cert_dir = _tofu_get_dioscuri_dir().join("cert");
+    if !cert_dir.is_dir() {
+        return;
+    }
+    let store = match store() {
+        Ok(store) => store,
+        Err(e) => {
+            println!("Not migrating {:?}: {:?}", cert_dir, e);
+            return;
         }
+    };
+    let mut known_hosts = store.lock().unwrap_or_else(|e| e.into_inner());
+    let (migrated, skipped) = _tofu_import_dir(&cert_dir, &mut known_hosts);
+    if let Err(e) = known_hosts.save() {
+        println!("Could not write known_hosts, keeping {:?}: {}", cert_dir, e);
+        return;
+    }
+    if skipped > 0 {
+        println!("Migrated {} certificate(s) from {:?} to known_hosts, keeping it for the {} skipped", migrated, cert_dir, skipped);
+        return;
+    }
+    let _ = fs::rename(&cert_dir, cert_dir.with_extension("migrated"));
+    println!("Migrated {} certificate(s) from {:?} to known_hosts", migrated, cert_dir);
+}
+
+/// Returns the pending verification failure for key, if any
+pub fn tofu_get_pending(key: &str) -> Option<TofuError> {
+    pending().lock().unwrap_or_else(|e| e.into_inner()).get(key).map(|(e, _)| e.clone())
+}
+
+/// Trusts the pending certificate for key until Dioscuri exits, without saving it.
+/// Returns false if no pending certificate with this fingerprint exists.
+pub fn tofu_trust_once(key: &str, fingerprint: &str) -> bool {
+    if _tofu_take_pending(key, fingerprint).is_none() {
+        return false;
+    }
+    trusted_once().lock().unwrap_or_else(|e| e.into_inner()).insert((key.to_string(), fingerprint.to_string()));
+    true
+}
+
+/// Replaces the known_hosts entry for key with the pending certificate.
+/// Mismatches and expired certificates can be trusted permanently. An expired certificate stays trusted
+/// for as long as the capsule presents it, see _tofu_check. Certificates that are not yet valid can only be trusted once.
+/// Returns false if no such pending certificate exists or the database could not be written.
+pub fn tofu_trust_permanently(key: &str, fingerprint: &str) -> bool {
+    let Some(host) = _tofu_take_pinnable(key, fingerprint) else {
+        return false;
+    };
+    let Ok(store) = store() else {
+        return false;
+    };
+    let mut known_hosts = store.lock().unwrap_or_else(|e| e.into_inner());
+    known_hosts.insert(host);
+    let saved = known_hosts.save().is_ok();
+    if saved {
+        known_hosts.unsaved_since = None;
     }
+    saved
 }
 
-/// Ensures that a certificate store directory cert/ is made in ~/.dioscuri (or the equivalent home directory)
-fn _tofu_setup_directory() -> Result<PathBuf, std::io::Error> {
-    let home_dir = dirs::home_dir().ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::NotFound, "Home directory does not exist?")
-    })?;
+/// Forgets the pending certificate for key. The database is left untouched.
+pub fn tofu_reject(key: &str) {
+    pending().lock().unwrap_or_else(|e| e.into_inner()).remove(key);
+}
+
+/// How a successful check changed known_hosts
+#[derive(Debug, PartialEq)]
+enum TofuUpdate {
+    Unchanged,
+    /// Only the last seen time of a known certificate changed
+    Seen,
+    /// A host was added, or its certificate was renewed with the same public key
+    Trusted,
+}
 
-    let dioscuri_dir = home_dir.join(".dioscuri/cert");
+/// Verifies cert_der for key against known_hosts at time now.
+/// Expired certificates are only rejected if check_expiry is set, see tofu.check_expiry in the config,
+/// and unless the user trusted this very certificate permanently after it expired.
+/// Such entries are the ones last seen after their expiry, which only happens when they were accepted expired.
+/// Returns how known_hosts was modified, which decides when it is saved.
+fn _tofu_check(known_hosts: &mut KnownHosts, cert_der: &[u8], key: &str, now: i64, check_expiry: bool) -> Result<TofuUpdate, TofuError> {
+    let Ok((_, target_cert)) = X509Certificate::from_der(cert_der) else {
+        return Err(TofuError::Invalid("Certificate from server is invalid".to_string()));
+    };
+    let candidate = _tofu_known_host(&target_cert, key, now);
+    let new_info = _tofu_cert_info(&candidate);
 
+    if trusted_once().lock().unwrap_or_else(|e| e.into_inner()).contains(&(key.to_string(), candidate.fingerprint.clone())) {
+        return Ok(TofuUpdate::Unchanged);
+    }
+
+    let validity = target_cert.validity();
+    let not_yet_valid = now < validity.not_before.timestamp();
+    let pinned_expired = known_hosts.get(key).is_some_and(|known| {
+        known.fingerprint == candidate.fingerprint && known.not_after == candidate.not_after && known.last_seen > known.not_after
+    });
+    if check_expiry && (not_yet_valid || (now > candidate.not_after && !pinned_expired)) {
+        let err = TofuError::Expired { key: key.to_string(), new: new_info, not_yet_valid };
+        return Err(_tofu_set_pending(err, candidate));
+    }
+
+    let Some(known) = known_hosts.get(key) else {
+        // Host does not exist in the database. Add to trust store (TOFU)
+        known_hosts.insert(candidate);
+        return Ok(TofuUpdate::Trusted);
+    };
+    if known.fingerprint != candidate.fingerprint {
+        // Abort if the public keys do not match, regardless of the known certificate's expiry
+        let old = _tofu_cert_info(known);
+        return Err(_tofu_set_pending(TofuError::Mismatch { key: key.to_string(), old, new: new_info }, candidate));
+    }
+    // Same public key, possibly a renewed certificate. Keep the first sighting.
+    let update = if known.not_after == candidate.not_after { TofuUpdate::Seen } else { TofuUpdate::Trusted };
+    let updated = KnownHost { first_seen: known.first_seen, ..candidate };
+    known_hosts.insert(updated);
+    Ok(update)
+}
+
+/// Builds the known_hosts entry for a certificate first and last seen at now
+fn _tofu_known_host(cert: &X509Certificate, key: &str, now: i64) -> KnownHost {
+    KnownHost {
+        key: key.to_string(),
+        fingerprint: _tofu_fingerprint(cert),
+        first_seen: now,
+        last_seen: now,
+        not_after: cert.validity().not_after.timestamp(),
+    }
+}
+
+/// Returns the SHA-256 fingerprint of a certificate's public key in lowercase hex
+fn _tofu_fingerprint(cert: &X509Certificate) -> String {
+    Sha256::digest(cert.public_key().raw)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn _tofu_cert_info(host: &KnownHost) -> CertInfo {
+    CertInfo {
+        fingerprint: host.fingerprint.clone(),
+        not_after: _tofu_format_time(host.not_after),
+    }
+}
+
+fn _tofu_format_time(timestamp: i64) -> String {
+    ASN1Time::from_timestamp(timestamp)
+        .map(|t| t.to_string())
+        .unwrap_or_else(|_| timestamp.to_string())
+}
+
+fn _tofu_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+/// Records err as pending along with the entry to trust if the user accepts, and returns err
+fn _tofu_set_pending(err: TofuError, candidate: KnownHost) -> TofuError {
+    let key = match &err {
+        TofuError::Mismatch { key, .. } | TofuError::Expired { key, .. } => key.clone(),
+        TofuError::Invalid(_) => return err,
+    };
+    pending().lock().unwrap_or_else(|e| e.into_inner()).insert(key, (err.clone(), candidate));
+    err
+}
+
+/// Removes and returns the pending entry for key if its presented certificate has this fingerprint
+fn _tofu_take_pending(key: &str, fingerprint: &str) -> Option<(TofuError, KnownHost)> {
+    let mut pending = pending().lock().unwrap_or_else(|e| e.into_inner());
+    let matches = match pending.get(key) {
+        Some((TofuError::Mismatch { new, .. }, _)) | Some((TofuError::Expired { new, .. }, _)) => new.fingerprint == fingerprint,
+        _ => false,
+    };
+    if matches { pending.remove(key) } else { None }
+}
+
+/// Takes the pending entry for key if the user may trust it permanently: mismatches and expired certificates.
+/// Other pending certificates are left for the user to decide on.
+fn _tofu_take_pinnable(key: &str, fingerprint: &str) -> Option<KnownHost> {
+    match _tofu_take_pending(key, fingerprint)? {
+        (TofuError::Mismatch { .. } | TofuError::Expired { not_yet_valid: false, .. }, host) => Some(host),
+        other => {
+            // put it back, nothing was decided
+            pending().lock().unwrap_or_else(|e| e.into_inner()).insert(key.to_string(), other);
+            None
+        }
+    }
+}
+
+/// Maps a legacy store file stem, the certificate's common name, to its key on the default port.
+/// Returns None for wildcard common names and other names that are not plain hostnames.
+fn _tofu_legacy_key(stem: &str) -> Option<String> {
+    let is_hostname = !stem.is_empty() && stem.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+    is_hostname.then(|| tofu_key(stem, DEFAULT_PORT))
+}
+
+/// Adds every certificate in a legacy store directory to known_hosts.
+/// Returns how many were added and how many were skipped because they could not be read or keyed.
+/// Hosts already in known_hosts are kept as-is. The file's modification time is used as first and last seen.
+fn _tofu_import_dir(dir: &Path, known_hosts: &mut KnownHosts) -> (usize, usize) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return (0, 1);
+    };
+    let mut migrated = 0;
+    let mut skipped = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("der") {
+            continue;
+        }
+        let Some(key) = path.file_stem().and_then(|s| s.to_str()).and_then(_tofu_legacy_key) else {
+            println!("Skipping certificate {:?}: not named after a host, cannot migrate", path);
+            skipped += 1;
+            continue;
+        };
+        if known_hosts.get(&key).is_some() {
+            continue;
+        }
+        let Ok(der) = fs::read(&path) else {
+            println!("Skipping certificate {:?}: unreadable", path);
+            skipped += 1;
+            continue;
+        };
+        let Ok((_, cert)) = X509Certificate::from_der(&der) else {
+            println!("Skipping certificate {:?}: invalid certificate", path);
+            skipped += 1;
+            continue;
+        };
+        let seen = entry.metadata().ok()
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_else(_tofu_now);
+        known_hosts.insert(_tofu_known_host(&cert, &key, seen));
+        migrated += 1;
+    }
+    (migrated, skipped)
+}
+
+/// Ensures that ~/.dioscuri (or the configured storage directory) exists and returns it
+fn _tofu_get_dioscuri_dir() -> PathBuf {
+    let dioscuri_dir = config::config_get().storage_dir();
     if !dioscuri_dir.exists() {
-        fs::create_dir_all(&dioscuri_dir)?;
+        let _ = fs::create_dir_all(&dioscuri_dir);
         println!("Creating directory: {:?}", dioscuri_dir);
     }
-    Ok(dioscuri_dir)
+    dioscuri_dir
 }
 
-fn _tofu_get_cert_dir() -> PathBuf {
-    let _ = _tofu_setup_directory();
-    let home = dirs::home_dir().unwrap();
-    return home.join(".dioscuri/cert");
+/// Returns tofu.known_hosts from the config, or known_hosts in the storage directory
+fn _tofu_get_known_hosts_path() -> PathBuf {
+    match &config::config_get().tofu.known_hosts {
+        Some(path) => path.clone(),
+        None => _tofu_get_dioscuri_dir().join(KNOWN_HOSTS_FILENAME),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_parser::pem::parse_x509_pem;
+
+    /// Two self-signed certificates for example.org with different keys, valid from 2026-10-17 until 2126
+    const CERT_A: &str = "-----BEGIN CERTIFICATE-----
+MIIBgzCCASmgAwIBAgIUEScVQWGoJePXr7artzy2q7osmSgwCgYIKoZIzj0EAwIw
+FjEUMBIGA1UEAwwLZXhhbXBsZS5vcmcwIBcNMjYxMDE3MjIyNjQyWhgPMjEyNjA5
+MjMyMjI2NDJaMBYxFDASBgNVBAMMC2V4YW1wbGUub3JnMFkwEwYHKoZIzj0CAQYI
+KoZIzj0DAQcDQgAENp7B/6ldiGUtdEgB44u4Fl6zmhohGKeycGX67jIcYTElkLq2
+Z5levKXzjQwKLXzflEhazaae1iEHrE1fryz0U6NTMFEwHQYDVR0OBBYEFM9kNFcB
+IvsccambWM0W0BOUsRRwMB8GA1UdIwQYMBaAFM9kNFcBIvsccambWM0W0BOUsRRw
+MA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSAAwRQIhAN77GfptZGW4z066
+qQMYdd4siUWxuR/wlDKKkQQeiFt/AiA1kunKieBLF1ESKjbtqbcSGNCSwzd0WFQO
+PVdXGu08UQ==
+-----END CERTIFICATE-----";
+    const CERT_B: &str = "-----BEGIN CERTIFICATE-----
+MIIBhDCCASmgAwIBAgIUOIoblL0TFBquoLGP7m8EZpYuVMkwCgYIKoZIzj0EAwIw
+FjEUMBIGA1UEAwwLZXhhbXBsZS5vcmcwIBcNMjYxMDE3MjIyNjQ2WhgPMjEyNjA5
+MjMyMjI2NDZaMBYxFDASBgNVBAMMC2V4YW1wbGUub3JnMFkwEwYHKoZIzj0CAQYI
+KoZIzj0DAQcDQgAEs6Uyy+8KFTUgi6kBJLYAcF4SR1LcQWfw+I8p+C2YgTxeZdJT
+kDHoW5poFPR8bw3zYRJSTbCsLBTXoSj0oPoo86NTMFEwHQYDVR0OBBYEFB8fXnPV
+XnA1WjCQpzRs8Q5N9xDnMB8GA1UdIwQYMBaAFB8fXnPVXnA1WjCQpzRs8Q5N9xDn
+MA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSQAwRgIhAICvd/7yU/0CXEXT
+6QaChiOJBs+v5GTphOtE8Hg05vaNAiEA9n2W91dLJKOKGcftyYJkHwYHG1I6nPsk
+OYYLFaKUIX4=
+-----END CERTIFICATE-----";
+    /// 2027-01-01, within the validity of both certificates
+    const NOW: i64 = 1_798_761_600;
+
+    fn der(pem: &str) -> Vec<u8> {
+        parse_x509_pem(pem.as_bytes()).unwrap().1.contents
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dioscuri-tofu-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn empty_store(dir: &Path) -> KnownHosts {
+        KnownHosts::load(&dir.join(KNOWN_HOSTS_FILENAME)).unwrap()
+    }
+
+    #[test]
+    fn test_first_use_then_match_then_mismatch() {
+        let dir = temp_dir("check");
+        let mut hosts = empty_store(&dir);
+        let key = "check.example:1965";
+
+        assert_eq!(_tofu_check(&mut hosts, &der(CERT_A), key, NOW, true), Ok(TofuUpdate::Trusted));
+        let first = hosts.get(key).unwrap().clone();
+        assert_eq!((first.first_seen, first.last_seen), (NOW, NOW));
+
+        // same key later updates last seen only
+        assert_eq!(_tofu_check(&mut hosts, &der(CERT_A), key, NOW + 10, true), Ok(TofuUpdate::Seen));
+        let second = hosts.get(key).unwrap();
+        assert_eq!((second.first_seen, second.last_seen), (NOW, NOW + 10));
+        assert_eq!(second.fingerprint, first.fingerprint);
+
+        // another key for the same host is refused and the entry is untouched
+        let Err(TofuError::Mismatch { old, new, .. }) = _tofu_check(&mut hosts, &der(CERT_B), key, NOW + 20, true) else {
+            panic!("expected a mismatch");
+        };
+        assert_eq!(old.fingerprint, first.fingerprint);
+        assert_ne!(new.fingerprint, first.fingerprint);
+        assert_eq!(hosts.get(key).unwrap().last_seen, NOW + 10);
+
+        // ...but the same certificate on another port is a different host
+        assert_eq!(_tofu_check(&mut hosts, &der(CERT_B), "check.example:1966", NOW, true), Ok(TofuUpdate::Trusted));
+
+        // trusting once skips the database entirely
+        assert!(tofu_trust_once(key, &new.fingerprint));
+        assert_eq!(_tofu_check(&mut hosts, &der(CERT_B), key, NOW + 30, true), Ok(TofuUpdate::Unchanged));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expired_certificate() {
+        let dir = temp_dir("expired");
+        let mut hosts = empty_store(&dir);
+        let key = "expired.example:1965";
+        let too_late = 5_000_000_000; // year 2128
+        let Err(TofuError::Expired { new, not_yet_valid: false, .. }) = _tofu_check(&mut hosts, &der(CERT_A), key, too_late, true) else {
+            panic!("expected an expired certificate");
+        };
+        assert!(hosts.get(key).is_none());
+        tofu_reject(key);
+        assert_eq!(tofu_get_pending(key), None);
+        assert!(!tofu_trust_once(key, &new.fingerprint));
+
+        // certificates that are not valid yet cannot be pinned
+        let too_early = 1_700_000_000; // 2023
+        let Err(TofuError::Expired { new, not_yet_valid: true, .. }) = _tofu_check(&mut hosts, &der(CERT_A), key, too_early, true) else {
+            panic!("expected a certificate that is not valid yet");
+        };
+        assert_eq!(_tofu_take_pinnable(key, &new.fingerprint), None);
+        assert!(tofu_get_pending(key).is_some());
+        tofu_reject(key);
+
+        // with tofu.check_expiry off, expired certificates are trusted like any other
+        assert_eq!(_tofu_check(&mut hosts, &der(CERT_A), key, too_late, false), Ok(TofuUpdate::Trusted));
+        assert!(hosts.get(key).is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pin_expired_certificate() {
+        let dir = temp_dir("pin-expired");
+        let mut hosts = empty_store(&dir);
+        let key = "pin-expired.example:1965";
+        let too_late = 5_000_000_000; // year 2128
+        // trusted while valid, then it expires
+        assert_eq!(_tofu_check(&mut hosts, &der(CERT_A), key, NOW, true), Ok(TofuUpdate::Trusted));
+        let Err(TofuError::Expired { new, .. }) = _tofu_check(&mut hosts, &der(CERT_A), key, too_late, true) else {
+            panic!("expected an expired certificate");
+        };
+        let host = _tofu_take_pinnable(key, &new.fingerprint).unwrap();
+        assert_eq!(tofu_get_pending(key), None);
+        hosts.insert(host);
+        hosts.save().unwrap();
+
+        // the pin survives a restart, and keeps being accepted while the capsule presents it
+        let mut hosts = empty_store(&dir);
+        assert_eq!(hosts.get(key).unwrap().last_seen, too_late);
+        assert_eq!(_tofu_check(&mut hosts, &der(CERT_A), key, too_late + 10, true), Ok(TofuUpdate::Seen));
+        // another key is still refused
+        assert!(matches!(_tofu_check(&mut hosts, &der(CERT_B), key, NOW, true), Err(TofuError::Mismatch { .. })));
+        assert!(matches!(_tofu_check(&mut hosts, &der(CERT_B), key, too_late + 20, true), Err(TofuError::Expired { .. })));
+        tofu_reject(key);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_known_hosts_roundtrip() {
+        let dir = temp_dir("roundtrip");
+        let path = dir.join(KNOWN_HOSTS_FILENAME);
+        let mut hosts = empty_store(&dir);
+        _tofu_check(&mut hosts, &der(CERT_A), "a.example:1965", NOW, true).unwrap();
+        _tofu_check(&mut hosts, &der(CERT_B), "[::1]:1966", NOW, true).unwrap();
+        hosts.save().unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("# dioscuri known_hosts v1\n"));
+        let loaded = KnownHosts::load(&path).unwrap();
+        assert_eq!(loaded.hosts, hosts.hosts);
+        // no temporary files are left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_known_hosts_rejects_bad_files() {
+        let dir = temp_dir("bad");
+        let path = dir.join(KNOWN_HOSTS_FILENAME);
+        fs::write(&path, "# dioscuri known_hosts v2\n").unwrap();
+        assert!(KnownHosts::load(&path).is_err());
+        fs::write(&path, "# dioscuri known_hosts v1\na.example:1965 nothex 1 2 3\n").unwrap();
+        assert!(KnownHosts::load(&path).is_err());
+        fs::write(&path, format!("# dioscuri known_hosts v1\n\n# comment\na.example:1965 {} 1 2 3\n", "ab".repeat(32))).unwrap();
+        assert_eq!(KnownHosts::load(&path).unwrap().get("a.example:1965").unwrap().not_after, 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_key() {
+        assert_eq!(_tofu_legacy_key("example.org"), Some("example.org:1965".to_string()));
+        assert_eq!(_tofu_legacy_key("Example.ORG"), Some("example.org:1965".to_string()));
+        assert_eq!(_tofu_legacy_key("*.wildcard.org"), None);
+        assert_eq!(_tofu_legacy_key("bad_name"), None);
+        assert_eq!(_tofu_legacy_key(""), None);
+    }
+
+    #[test]
+    fn test_import_legacy_dir() {
+        let dir = temp_dir("import");
+        let cert_dir = dir.join("cert");
+        fs::create_dir_all(&cert_dir).unwrap();
+        fs::write(cert_dir.join("example.org.der"), der(CERT_A)).unwrap();
+        fs::write(cert_dir.join("other.org.der"), der(CERT_B)).unwrap();
+        fs::write(cert_dir.join("*.wildcard.org.der"), der(CERT_A)).unwrap();
+        fs::write(cert_dir.join("broken.org.der"), b"not a certificate").unwrap();
+        fs::write(cert_dir.join("known.org.der"), der(CERT_A)).unwrap();
+
+        let mut hosts = empty_store(&dir);
+        _tofu_check(&mut hosts, &der(CERT_B), "known.org:1965", NOW, true).unwrap();
+        // the wildcard and the broken file are skipped
+        assert_eq!(_tofu_import_dir(&cert_dir, &mut hosts), (2, 2));
+        let a_der = der(CERT_A);
+        let a = X509Certificate::from_der(&a_der).unwrap().1;
+        assert_eq!(hosts.get("example.org:1965").unwrap().fingerprint, _tofu_fingerprint(&a));
+        assert!(hosts.get("other.org:1965").is_some());
+        assert!(hosts.hosts.keys().all(|key| !key.contains('*')));
+        // existing entries win over the legacy store
+        let b_der = der(CERT_B);
+        let b = X509Certificate::from_der(&b_der).unwrap().1;
+        assert_eq!(hosts.get("known.org:1965").unwrap().fingerprint, _tofu_fingerprint(&b));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
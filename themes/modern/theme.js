@@ -0,0 +1,19 @@
+document.addEventListener('DOMContentLoaded', () => {
+    const toggleButton = document.getElementById('darkModeToggle');
+    const body = document.body;
+
+    function setTheme(theme) {
+        body.setAttribute('theme', theme);
+        localStorage.setItem('theme', theme);
+        toggleButton.textContent = theme === 'dark' ? '🌙 Mode' : '🌞 Mode';
+    }
+
+    const savedTheme = localStorage.getItem('theme') || 'light';
+    setTheme(savedTheme);
+
+    toggleButton.addEventListener('click', () => {
+        const currentTheme = body.getAttribute('theme');
+        const newTheme = currentTheme === 'dark' ? 'light' : 'dark';
+        setTheme(newTheme);
+    });
+});
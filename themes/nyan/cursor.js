@@ -0,0 +1,59 @@
+// Draws a rainbow trail behind the mouse pointer, like the rainbowCursor effect of cursor-effects
+// (https://github.com/tholman/cursor-effects) this theme used to load from unpkg.
+// It is served from /.src/ because the content security policy blocks scripts from other sites.
+(function () {
+    if (window.matchMedia("(prefers-reduced-motion: reduce)").matches) {
+        return;
+    }
+    var colors = ["#FE0000", "#FD8C00", "#FFE500", "#119F0B", "#0644B3", "#C22EDC"];
+    var length = 20;
+    var size = 3;
+    var canvas = document.createElement("canvas");
+    var context = canvas.getContext("2d");
+    var cursor = null;
+    var trail = [];
+
+    canvas.setAttribute("aria-hidden", "true");
+    canvas.style.cssText = "position: fixed; top: 0; left: 0; pointer-events: none; z-index: 9999;";
+
+    function resize() {
+        canvas.width = window.innerWidth;
+        canvas.height = window.innerHeight;
+    }
+
+    function draw() {
+        if (cursor) {
+            trail.unshift({ x: cursor.x, y: cursor.y });
+            trail.length = Math.min(trail.length, length);
+        }
+        context.clearRect(0, 0, canvas.width, canvas.height);
+        if (trail.length > 1) {
+            context.lineWidth = size;
+            colors.forEach(function (color, index) {
+                var offset = (index - colors.length / 2) * size;
+                context.strokeStyle = color;
+                context.beginPath();
+                context.moveTo(trail[0].x, trail[0].y + offset);
+                trail.forEach(function (point) {
+                    context.lineTo(point.x, point.y + offset);
+                });
+                context.stroke();
+            });
+        }
+        window.requestAnimationFrame(draw);
+    }
+
+    window.addEventListener("resize", resize);
+    document.addEventListener("mousemove", function (event) {
+        cursor = { x: event.clientX, y: event.clientY };
+    });
+    document.addEventListener("mouseleave", function () {
+        cursor = null;
+        trail = [];
+    });
+    window.addEventListener("load", function () {
+        resize();
+        document.body.appendChild(canvas);
+        draw();
+    });
+})();